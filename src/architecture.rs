@@ -0,0 +1,66 @@
+use crate::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+/* ELF e_machine values */
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+/* Mozilla os id of a machine name, as reported by uname -m */
+pub fn from_machine(machine: &str) -> Option<&'static str> {
+    match machine {
+        "x86_64" | "amd64" => Some("linux64"),
+        "i386" | "i486" | "i586" | "i686" | "x86" => Some("linux"),
+        "aarch64" | "arm64" | "aarch64_be" => Some("linux64-aarch64"),
+        _ => None,
+    }
+}
+
+/*
+    Mozilla os id of an ELF executable, read from its header, e.g. "linux64"
+    for an x86_64 binary
+*/
+pub fn from_elf(path: &Path) -> Result<&'static str,Error> {
+    let mut header = [0u8; 20];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .map_err(|e| Error::io(path, e))?;
+    if &header[0..4] != b"\x7fELF" {
+        return Err(Error::new(&format!("{} is not an ELF executable", path.display())));
+    }
+
+    /* e_machine follows e_ident, e_type, in the file's byte order */
+    let machine = match header[5] {
+        2 => u16::from_be_bytes([header[18], header[19]]),
+        _ => u16::from_le_bytes([header[18], header[19]]),
+    };
+    match (header[4], machine) {
+        (1, EM_386) => Ok("linux"),
+        (2, EM_X86_64) => Ok("linux64"),
+        (2, EM_AARCH64) => Ok("linux64-aarch64"),
+        (class, machine) => Err(Error::new(&format!(
+            "{} is built for an unsupported architecture (class {}, machine {})", path.display(), class, machine))),
+    }
+}
+
+/*
+    Architecture of the running system. The userland decides, since a 64-bit
+    kernel may run a 32-bit system: /bin/sh is checked first, then the
+    kernel's machine name. None if it can't be determined.
+*/
+pub fn detect() -> Option<&'static str> {
+    if let Ok(os) = from_elf(Path::new("/bin/sh")) {
+        return Some(os);
+    }
+
+    let output = Command::new("uname").arg("-m").output().ok()?;
+    from_machine(String::from_utf8_lossy(&output.stdout).trim())
+}
+
+/* Whether a system of architecture `host` can execute `os` builds, given the 32-bit libraries for linux on linux64 */
+pub fn can_run(host: &str, os: &str) -> bool {
+    host == os || (host == "linux64" && os == "linux")
+}
@@ -0,0 +1,225 @@
+use crate::{CancelToken, Error};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path};
+use std::rc::Rc;
+
+/*
+    Reader that keeps track of how many bytes were consumed from the
+    compressed archive, which is our only measure of progress. It also
+    stops reading once cancelled, so that large entries are interrupted too.
+*/
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+    cancel: CancelToken,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(std::io::Error::other("cancelled"));
+        }
+        let len = self.inner.read(buf)?;
+        self.count.set(self.count.get() + len as u64);
+        Ok(len)
+    }
+}
+
+enum Compression {
+    Bzip2,
+    Xz,
+    Gzip,
+}
+
+fn detect_compression(archive: &Path) -> Result<Compression,Error> {
+    let mut magic = [ 0u8; 6 ];
+    let mut f = File::open(archive).map_err(|e| Error::io(archive, e))?;
+    f.read_exact(&mut magic)
+        .map_err(|_| Error::Extraction(format!("{} is not a valid archive", archive.display())))?;
+
+    if magic.starts_with(b"BZh") {
+        Ok(Compression::Bzip2)
+    } else if magic == [ 0xFD, b'7', b'z', b'X', b'Z', 0x00 ] {
+        Ok(Compression::Xz)
+    } else if magic.starts_with(&[ 0x1F, 0x8B ]) {
+        Ok(Compression::Gzip)
+    } else {
+        Err(Error::Extraction(format!("Unsupported archive format: {}", archive.display())))
+    }
+}
+
+/* Fails early for files that are not an archive we can extract */
+pub fn check_format(archive: &Path) -> Result<(),Error> {
+    detect_compression(archive).map(|_| ())
+}
+
+/*
+    Whether `path`, relative to the extraction directory, stays inside it
+    once its `..` components are resolved
+*/
+fn is_contained(path: &Path) -> bool {
+    let mut depth = 0i32;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/*
+    Extract a .tar.bz2, .tar.xz or .tar.gz archive into destdir.
+    Entries whose path or link target would end up outside of destdir are
+    rejected. When cancelled, what was extracted so far is left for the
+    caller to remove.
+*/
+pub fn extract<F>(archive: &Path, destdir: &Path, cancel: &CancelToken, progress: F) -> Result<(),Error>
+where
+    F: Fn(i64, i64)
+{
+    let result = extract_entries(archive, destdir, cancel, progress);
+    cancel.check()?;
+    result
+}
+
+fn extract_entries<F>(archive: &Path, destdir: &Path, cancel: &CancelToken, progress: F) -> Result<(),Error>
+where
+    F: Fn(i64, i64)
+{
+    let compression = detect_compression(archive)?;
+    let total = std::fs::metadata(archive)?.len() as i64;
+    let count = Rc::new(Cell::new(0u64));
+    let reader = CountingReader {
+        inner: BufReader::new(File::open(archive)?),
+        count: Rc::clone(&count),
+        cancel: cancel.clone(),
+    };
+    let reader: Box<dyn Read> = match compression {
+        Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(reader)),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+    };
+
+    std::fs::create_dir_all(destdir).map_err(|e| Error::io(destdir, e))?;
+    let mut tar = tar::Archive::new(reader);
+    tar.set_preserve_permissions(true);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !is_contained(&path) {
+            return Err(Error::Extraction(format!("Archive entry escapes the install directory: {}", path.display())));
+        }
+
+        if let Some(link) = entry.link_name()? {
+            let target = if entry.header().entry_type().is_hard_link() {
+                link.to_path_buf()
+            } else {
+                path.parent().unwrap_or_else(|| Path::new("")).join(&link)
+            };
+            if link.is_absolute() || !is_contained(&target) {
+                return Err(Error::Extraction(format!("Archive link escapes the install directory: {} -> {}", path.display(), link.display())));
+            }
+        }
+
+        if !entry.unpack_in(destdir)? {
+            return Err(Error::Extraction(format!("Cannot extract {}", path.display())));
+        }
+        progress(count.get() as i64, total);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{EntryType, Header};
+
+    /*
+        A .tar.bz2 of (path, link target) entries, written without the
+        checks tar::Builder makes so that malicious paths get through
+    */
+    fn raw_tar_bz2(entries: &[(&str, Option<&str>)]) -> Vec<u8> {
+        let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, link) in entries {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            let contents: &[u8] = match link {
+                Some(link) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+                    b""
+                }
+                None => b"data",
+            };
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /*
+        Extracts into <tmp>/dest, returns the result and what ended up in
+        <tmp>. Links found in dest count as being outside of it.
+    */
+    fn extract_raw(entries: &[(&str, Option<&str>)]) -> (Result<(),Error>, Vec<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("firefox.tar.bz2");
+        std::fs::write(&archive, raw_tar_bz2(entries)).unwrap();
+        let result = extract(&archive, &dir.path().join("dest"), &CancelToken::new(), |_,_| {});
+        let mut files: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        let mut pending = vec![ dir.path().join("dest") ];
+        while let Some(path) = pending.pop() {
+            for entry in std::fs::read_dir(&path).into_iter().flatten().flatten() {
+                if entry.file_type().unwrap().is_symlink() {
+                    files.push(entry.path().display().to_string());
+                } else {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        (result, files)
+    }
+
+    #[test]
+    fn extract_contained() {
+        let (result, _) = extract_raw(&[
+            ("firefox/firefox", None),
+            ("firefox/lib/libxul.so", None),
+            ("firefox/libxul.so", Some("lib/libxul.so")),
+            ("firefox/lib/self", Some("../../firefox")),
+        ]);
+        result.unwrap();
+    }
+
+    #[test]
+    fn reject_escaping_entries() {
+        let escaping: &[&[(&str, Option<&str>)]] = &[
+            &[ ("firefox/../../escaped", None) ],
+            &[ ("firefox/firefox", None), ("firefox/etc", Some("/etc")) ],
+            &[ ("firefox/firefox", None), ("firefox/x", Some("../../etc")) ],
+        ];
+        for entries in escaping {
+            match extract_raw(entries) {
+                (Err(Error::Extraction(_)), files) => assert_eq!(files, vec![ "dest", "firefox.tar.bz2" ], "{:?}", entries),
+                (other, _) => panic!("{:?} extracted: {:?}", entries, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use crate::{languages, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/* Bump when the meaning of an existing field changes */
+pub const CONFIG_VERSION: u32 = 1;
+
+/*
+    Settings used to install a channel, so that it can be reinstalled or
+    repaired without asking again
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct InstallConfig {
+    pub architecture: String,
+    pub language: String,
+    /* Pinned version, e.g. "115.8.0esr". None follows the latest version. */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/*
+    HTTP settings. Unset fields keep the downloader's defaults, and without
+    a proxy the http_proxy, https_proxy and no_proxy environment variables
+    are used.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /* e.g. "http://proxy.example.com:3128" */
+    pub proxy: Option<String>,
+    /* Comma-separated hosts or domains reached without the proxy */
+    pub no_proxy: Option<String>,
+    /* PEM file with additional trusted certificates */
+    pub ca_bundle: Option<PathBuf>,
+    /* In seconds */
+    pub connect_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+impl NetworkConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == NetworkConfig::default()
+    }
+
+    /* Fields set in `self` win */
+    fn or(&self, other: &NetworkConfig) -> NetworkConfig {
+        NetworkConfig {
+            proxy: self.proxy.clone().or_else(|| other.proxy.clone()),
+            no_proxy: self.no_proxy.clone().or_else(|| other.no_proxy.clone()),
+            ca_bundle: self.ca_bundle.clone().or_else(|| other.ca_bundle.clone()),
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            timeout: self.timeout.or(other.timeout),
+            retries: self.retries.or(other.retries),
+        }
+    }
+}
+
+/*
+    Per-user installer configuration, stored as config.json in the data dir.
+    Every field has a default, and fields we don't know about (written by a
+    newer version) are kept as-is when saving.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+    /* Last choices made in the dialog, also the channel launched by default */
+    pub channel: Option<String>,
+    pub architecture: Option<String>,
+    pub language: Option<String>,
+    /* Installed channels */
+    pub installs: BTreeMap<String,InstallConfig>,
+    #[serde(skip_serializing_if = "NetworkConfig::is_empty")]
+    pub network: NetworkConfig,
+    #[serde(flatten)]
+    extra: serde_json::Map<String,serde_json::Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            channel: None,
+            architecture: None,
+            language: None,
+            installs: BTreeMap::new(),
+            network: NetworkConfig::default(),
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+impl Config {
+    /*
+        A missing file is not an error, it just means nothing was installed yet
+    */
+    pub fn load(path: &Path) -> Result<Config,Error> {
+        match File::open(path) {
+            Ok(f) => {
+                let mut config: Config = serde_json::from_reader(f)
+                    .map_err(|e| Error::Config(format!("Invalid configuration file {}: {}", path.display(), e)))?;
+                config.version = config.version.max(CONFIG_VERSION);
+                Ok(config)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /*
+        Written to a temporary file first, so that a crash never leaves a
+        truncated configuration behind
+    */
+    pub fn save(&self, path: &Path) -> Result<(),Error> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut tmpfile = tempfile::Builder::new()
+            .prefix("config_")
+            .tempfile_in(dir)?;
+        serde_json::to_writer_pretty(tmpfile.as_file_mut(), self)?;
+        tmpfile.as_file_mut().write_all(b"\n")?;
+        tmpfile.persist(path).map_err(|e| Error::from(e.error))?;
+        Ok(())
+    }
+
+    /* An empty language is unknown, e.g. for an archive installed as-is */
+    pub fn record_install(&mut self, channel: &str, architecture: &str, language: &str, version: Option<&str>) {
+        self.channel = Some(String::from(channel));
+        self.architecture = Some(String::from(architecture));
+        if !language.is_empty() {
+            self.language = Some(String::from(language));
+        }
+        self.installs.insert(String::from(channel), InstallConfig {
+            architecture: String::from(architecture),
+            language: String::from(language),
+            version: version.map(String::from),
+        });
+    }
+}
+
+/*
+    System-wide defaults, read from the .conf files of a directory in
+    lexical order. Files are made of "key = value" lines, with '#' comments:
+
+        channel = esr
+        language = de
+        architecture = linux64
+        locked = channel, language
+        proxy = http://proxy.example.com:3128
+        no_proxy = localhost, .example.com
+        ca_bundle = /etc/ssl/certs/example-ca.pem
+        connect_timeout = 10
+        timeout = 30
+        retries = 3
+        mirror = https://mirror.example.com/pub/firefox
+
+    Later files override earlier ones, except for "locked" which accumulates.
+    Locked fields can't be changed by the user.
+
+    A line that can't be understood is skipped and reported as a problem,
+    the rest of the configuration still applies. A locked field whose value
+    is invalid keeps its lock, and makes installing fail.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct SystemConfig {
+    pub channel: Option<String>,
+    pub architecture: Option<String>,
+    pub language: Option<String>,
+    pub network: NetworkConfig,
+    /* Base url of a mirror laid out like archive.mozilla.org/pub/firefox */
+    pub mirror: Option<String>,
+    locked: BTreeSet<String>,
+    /* Fields whose value was rejected */
+    invalid: BTreeSet<String>,
+    problems: Vec<String>,
+}
+
+impl SystemConfig {
+    pub fn load_dir(dir: &Path) -> Result<SystemConfig,Error> {
+        let mut result = SystemConfig::default();
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+            Err(e) => return Err(Error::io(dir, e)),
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|ext| ext == "conf").unwrap_or(false))
+            .collect();
+        files.sort();
+
+        for file in files.iter() {
+            match std::fs::read_to_string(file) {
+                Ok(contents) => result.parse(file, &contents),
+                Err(e) => result.problems.push(Error::io(file, e).to_string()),
+            }
+        }
+        Ok(result)
+    }
+
+    fn parse(&mut self, file: &Path, contents: &str) {
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let location = format!("{}:{}", file.display(), index + 1);
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), String::from(value.trim())),
+                None => {
+                    self.problems.push(format!("{}: expected \"key = value\"", location));
+                    continue;
+                }
+            };
+            let number = value.parse::<u64>();
+            let retries = value.parse::<u32>();
+            match key {
+                "connect_timeout" | "timeout" if number.is_err() => {
+                    self.problems.push(format!("{}: expected a number", location));
+                }
+                "retries" if retries.is_err() => {
+                    self.problems.push(format!("{}: expected a number of retries", location));
+                }
+                "channel" => self.channel = Some(value),
+                "architecture" => self.architecture = Some(value),
+                "language" => self.language = Some(value),
+                "proxy" => self.network.proxy = Some(value),
+                "no_proxy" => self.network.no_proxy = Some(value),
+                "ca_bundle" => self.network.ca_bundle = Some(PathBuf::from(value)),
+                "connect_timeout" => self.network.connect_timeout = number.ok(),
+                "timeout" => self.network.timeout = number.ok(),
+                "retries" => self.network.retries = retries.ok(),
+                "mirror" => self.mirror = Some(value),
+                "locked" => {
+                    for field in value.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+                        self.locked.insert(String::from(field));
+                    }
+                }
+                /* Unknown keys are ignored, they may be meant for a newer version */
+                _ => {}
+            }
+        }
+    }
+
+    /*
+        Check the values set by the administrator. `find_channel` maps a
+        channel or product name to the channel id, e.g.
+        "firefox-esr-latest-ssl" to "esr", which replaces the configured value.
+        Invalid values are dropped and reported in problems().
+    */
+    pub fn validate<F>(&mut self, find_channel: F, architectures: &[&str])
+    where
+        F: Fn(&str) -> Option<&'static str>
+    {
+        if let Some(channel) = self.channel.take() {
+            match find_channel(&channel) {
+                Some(id) => self.channel = Some(String::from(id)),
+                None => self.reject("channel", &channel),
+            }
+        }
+        if let Some(architecture) = self.architecture.take() {
+            if architectures.contains(&architecture.as_str()) {
+                self.architecture = Some(architecture);
+            } else {
+                self.reject("architecture", &architecture);
+            }
+        }
+        if let Some(language) = self.language.take() {
+            if languages::is_known_locale(&language) {
+                self.language = Some(language);
+            } else {
+                self.reject("language", &language);
+            }
+        }
+    }
+
+    fn reject(&mut self, field: &str, value: &str) {
+        self.problems.push(format!("Invalid {} \"{}\" in the system configuration", field, value));
+        self.invalid.insert(String::from(field));
+    }
+
+    /* What was skipped while loading, for the administrator to fix */
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    fn value(&self, field: &str) -> Option<&String> {
+        match field {
+            "channel" => self.channel.as_ref(),
+            "architecture" => self.architecture.as_ref(),
+            "language" => self.language.as_ref(),
+            _ => None,
+        }
+    }
+
+    /* A lock without a value to lock to is meaningless, and ignored, unless the value was invalid */
+    pub fn is_locked(&self, field: &str) -> bool {
+        self.locked.contains(field) && (self.value(field).is_some() || self.invalid.contains(field))
+    }
+
+    /* Whether there is nothing left for the user to choose */
+    pub fn all_locked(&self) -> bool {
+        self.is_locked("channel") && self.is_locked("architecture") && self.is_locked("language")
+    }
+
+    /*
+        Locks must not fail open: nothing may be installed while a locked
+        field has an invalid value
+    */
+    pub fn check_locks(&self) -> Result<(),Error> {
+        match self.invalid.iter().find(|field| self.locked.contains(*field)) {
+            Some(field) => Err(Error::Config(format!(
+                "The {} is locked by the system administrator to an invalid value", field))),
+            None => Ok(()),
+        }
+    }
+
+    /*
+        Check a value requested by the user against the locks, e.g.
+        check("channel", "nightly")
+    */
+    pub fn check(&self, field: &str, value: &str) -> Result<(),Error> {
+        self.check_locks()?;
+        match self.value(field) {
+            Some(locked) if self.is_locked(field) && locked != value => {
+                Err(Error::Config(format!("The {} is locked to {} by the system administrator", field, locked)))
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+impl Config {
+    /*
+        Settings in effect: the user's choices on top of the system defaults,
+        with locked system settings taking precedence over both. The result
+        is not meant to be saved.
+    */
+    pub fn merged(&self, system: &SystemConfig) -> Config {
+        fn merge(user: &mut Option<String>, system: &SystemConfig, field: &str) {
+            if system.is_locked(field) || user.is_none() {
+                if let Some(value) = system.value(field) {
+                    *user = Some(value.clone());
+                }
+            }
+        }
+
+        let mut result = self.clone();
+        merge(&mut result.channel, system, "channel");
+        merge(&mut result.architecture, system, "architecture");
+        merge(&mut result.language, system, "language");
+        result.network = self.network.or(&system.network);
+        for install in result.installs.values_mut() {
+            if system.is_locked("architecture") {
+                install.architecture = system.architecture.clone().unwrap_or_default();
+            }
+            if system.is_locked("language") {
+                install.language = system.language.clone().unwrap_or_default();
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn system_config(contents: &str) -> SystemConfig {
+        let mut result = SystemConfig::default();
+        result.parse(Path::new("test.conf"), contents);
+        result
+    }
+
+    fn find_channel(channel: &str) -> Option<&'static str> {
+        [ ("release", "firefox-latest-ssl"), ("esr", "firefox-esr-latest-ssl") ].iter()
+            .find(|elem| elem.0 == channel || elem.1 == channel)
+            .map(|elem| elem.0)
+    }
+
+    fn validate(contents: &str) -> SystemConfig {
+        let mut result = system_config(contents);
+        result.validate(find_channel, &[ "linux64", "linux" ]);
+        result
+    }
+
+    #[test]
+    fn locked_product_id() {
+        let system = validate("channel = firefox-esr-latest-ssl\nlocked = channel\n");
+        assert_eq!(system.channel.as_deref(), Some("esr"));
+        assert!(system.check("channel", "esr").is_ok());
+        assert!(system.check("channel", "release").is_err());
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(validate("channel = esr\narchitecture = linux\nlanguage = de\n").problems().is_empty());
+        for contents in [ "channel = aurora", "architecture = x86_64", "language = de_DE", "language = ja-JP-mac" ] {
+            assert_eq!(validate(contents).problems().len(), 1, "{}", contents);
+        }
+    }
+
+    /* A mistake only costs the setting it is in */
+    #[test]
+    fn invalid_fields_are_skipped() {
+        let system = validate("channel = esr\nlanguage = german\ntimeout = soon\nretries 3\nproxy = http://proxy:3128\nlocked = channel\n");
+        assert_eq!(system.problems().len(), 3);
+        assert_eq!(system.channel.as_deref(), Some("esr"));
+        assert_eq!(system.language, None);
+        assert_eq!(system.network.timeout, None);
+        assert_eq!(system.network.proxy.as_deref(), Some("http://proxy:3128"));
+        assert!(system.check("channel", "esr").is_ok());
+        assert!(system.check("channel", "release").is_err());
+        assert!(!system.is_locked("language"));
+
+        let system = validate("retries = 4294967296\n");
+        assert_eq!(system.problems().len(), 1);
+        assert_eq!(system.network.retries, None);
+    }
+
+    /* An invalid locked value must not unlock the field */
+    #[test]
+    fn invalid_locked_field_fails_closed() {
+        let system = validate("channel = esr\narchitecture = amd64\nlocked = channel, architecture\n");
+        assert!(system.is_locked("architecture"));
+        assert!(matches!(system.check_locks(), Err(Error::Config(_))));
+        assert!(matches!(system.check("architecture", "linux64"), Err(Error::Config(_))));
+        assert!(matches!(system.check("channel", "esr"), Err(Error::Config(_))));
+    }
+
+    /* Installing an archive keeps the pin, and does not forget the language */
+    #[test]
+    fn record_unknown_language() {
+        let mut config = Config::default();
+        config.record_install("esr", "linux64", "de", Some("115.8.0esr"));
+        config.record_install("esr", "linux64", "", Some("115.8.0esr"));
+        assert_eq!(config.language.as_deref(), Some("de"));
+        assert_eq!(config.installs["esr"].language, "");
+        assert_eq!(config.installs["esr"].version.as_deref(), Some("115.8.0esr"));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::Error;
+use chrono::Utc;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/*
+    What is installed in a firefox directory, as reported by its
+    application.ini, platform.ini and channel-prefs.js, plus the settings it
+    was installed with
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct InstallInfo {
+    pub name: String,
+    pub version: String,
+    pub build_id: String,
+    pub update_channel: String,
+    /*
+        Version as named by product-details or the archive when installed,
+        e.g. "115.8.0esr" or "122.0b3", while `version` is App.Version
+        ("115.8.0", "122.0")
+    */
+    pub display_version: String,
+    pub architecture: String,
+    pub language: String,
+    pub installed: i64,
+}
+
+/*
+    Minimal ini parser: returns "Section.Key" => value
+*/
+fn parse_ini(contents: &str) -> HashMap<String,String> {
+    let mut result = HashMap::new();
+    let mut section = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = String::from(&line[1..line.len() - 1]);
+        } else if let Some((key, value)) = line.split_once('=') {
+            result.insert(format!("{}.{}", section, key.trim()), String::from(value.trim()));
+        }
+    }
+    result
+}
+
+fn read_ini(path: &Path) -> Result<HashMap<String,String>,Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::io(path, e))?;
+    Ok(parse_ini(&contents))
+}
+
+/* App.Version of a product-details version: "115.8.0esr" => "115.8.0", "122.0b3" => "122.0" */
+fn base_version(version: &str) -> &str {
+    let version = version.trim_end_matches("esr");
+    match version.find('b') {
+        Some(index) => &version[..index],
+        None => version,
+    }
+}
+
+impl InstallInfo {
+    /*
+        Read version information from an extracted firefox directory
+        (the one containing the firefox executable)
+    */
+    pub fn read(firefox_dir: &Path) -> Result<InstallInfo,Error> {
+        let application = read_ini(&firefox_dir.join("application.ini"))?;
+        let platform = read_ini(&firefox_dir.join("platform.ini")).unwrap_or_default();
+
+        let get = |ini: &HashMap<String,String>, key: &str| ini.get(key).cloned().unwrap_or_default();
+        let mut result = InstallInfo {
+            name: get(&application, "App.Name"),
+            version: get(&application, "App.Version"),
+            build_id: get(&application, "App.BuildID"),
+            ..InstallInfo::default()
+        };
+        if result.version.is_empty() {
+            result.version = get(&platform, "Build.Milestone");
+        }
+        if result.build_id.is_empty() {
+            result.build_id = get(&platform, "Build.BuildID");
+        }
+
+        /* pref("app.update.channel", "release"); */
+        if let Ok(prefs) = std::fs::read_to_string(firefox_dir.join("defaults/pref/channel-prefs.js")) {
+            let rex = Regex::new(r#"pref\(\s*"app\.update\.channel"\s*,\s*"(.*?)"\s*\)"#).unwrap();
+            if let Some(captures) = rex.captures(&prefs) {
+                result.update_channel = String::from(&captures[1]);
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn load(path: &Path) -> Result<InstallInfo,Error> {
+        let f = File::open(path).map_err(|e| Error::io(path, e))?;
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(),Error> {
+        let f = File::create(path).map_err(|e| Error::io(path, e))?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    pub fn mark_installed(&mut self, architecture: &str, language: &str, version: Option<&str>) {
+        self.architecture = String::from(architecture);
+        self.language = String::from(language);
+        self.display_version = String::from(version.unwrap_or_default());
+        self.installed = Utc::now().timestamp();
+    }
+
+    /*
+        The recorded display version, unless firefox updated itself since:
+        then only App.Version is known
+    */
+    pub fn full_version(&self) -> &str {
+        if !self.display_version.is_empty() && base_version(&self.display_version) == base_version(&self.version) {
+            &self.display_version
+        } else {
+            &self.version
+        }
+    }
+
+    /*
+        Whether `version`, as named by product-details or the archive, is the
+        one installed. Without a display version, betas can't be told apart.
+    */
+    pub fn has_version(&self, version: &str) -> bool {
+        let installed = self.full_version();
+        if installed == self.display_version {
+            return installed == version;
+        }
+        installed == base_version(version) && !version.contains('b')
+    }
+
+    /*
+        Firefox updates itself, so the version recorded at install time may
+        be stale: refresh it from the files on disk
+    */
+    pub fn refresh(&mut self, firefox_dir: &Path) -> Result<(),Error> {
+        let current = InstallInfo::read(firefox_dir)?;
+        self.name = current.name;
+        self.version = current.version;
+        self.build_id = current.build_id;
+        self.update_channel = current.update_channel;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed(version: &str, display_version: &str) -> InstallInfo {
+        InstallInfo {
+            version: String::from(version),
+            display_version: String::from(display_version),
+            ..InstallInfo::default()
+        }
+    }
+
+    #[test]
+    fn has_version() {
+        assert!(installed("121.0", "121.0").has_version("121.0"));
+        assert!(!installed("121.0", "121.0").has_version("121.0.1"));
+        assert!(installed("115.8.0", "115.8.0esr").has_version("115.8.0esr"));
+        assert!(installed("122.0", "122.0b3").has_version("122.0b3"));
+        assert!(!installed("122.0", "122.0b3").has_version("122.0b5"));
+        assert!(installed("130.0a1", "130.0a1").has_version("130.0a1"));
+    }
+
+    #[test]
+    fn has_version_without_display_version() {
+        assert!(installed("121.0", "").has_version("121.0"));
+        assert!(installed("115.8.0", "").has_version("115.8.0esr"));
+        assert!(!installed("122.0", "").has_version("122.0b3"));
+    }
+
+    #[test]
+    fn self_updated_install() {
+        let info = installed("115.9.0", "115.8.0esr");
+        assert_eq!(info.full_version(), "115.9.0");
+        assert!(!info.has_version("115.8.0esr"));
+        assert!(info.has_version("115.9.0esr"));
+    }
+}
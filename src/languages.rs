@@ -0,0 +1,414 @@
+use crate::{Downloader, Error};
+
+/*
+    Locales shipped with Firefox and their native names, used when
+    product-details can't be reached
+*/
+pub const FALLBACK_LANGUAGES: &[(&str, &str)] = &[
+    ("ach", "Acholi"),
+    ("af", "Afrikaans"),
+    ("an", "Aragonés"),
+    ("ar", "عربي"),
+    ("ast", "Asturianu"),
+    ("az", "Azərbaycanca"),
+    ("be", "Беларуская"),
+    ("bg", "Български"),
+    ("bn", "বাংলা"),
+    ("br", "Brezhoneg"),
+    ("bs", "Bosanski"),
+    ("ca", "Català"),
+    ("ca-valencia", "Català (Valencià)"),
+    ("cak", "Kaqchikel"),
+    ("cs", "Čeština"),
+    ("cy", "Cymraeg"),
+    ("da", "Dansk"),
+    ("de", "Deutsch"),
+    ("dsb", "Dolnoserbšćina"),
+    ("el", "Ελληνικά"),
+    ("en-CA", "English (Canadian)"),
+    ("en-GB", "English (British)"),
+    ("en-US", "English (US)"),
+    ("eo", "Esperanto"),
+    ("es-AR", "Español (de Argentina)"),
+    ("es-CL", "Español (de Chile)"),
+    ("es-ES", "Español (de España)"),
+    ("es-MX", "Español (de México)"),
+    ("et", "Eesti keel"),
+    ("eu", "Euskara"),
+    ("fa", "فارسی"),
+    ("ff", "Pulaar-Fulfulde"),
+    ("fi", "suomi"),
+    ("fr", "Français"),
+    ("fur", "Furlan"),
+    ("fy-NL", "Frysk"),
+    ("ga-IE", "Gaeilge"),
+    ("gd", "Gàidhlig"),
+    ("gl", "Galego"),
+    ("gn", "Guarani"),
+    ("gu-IN", "ગુજરાતી (ભારત)"),
+    ("he", "עברית"),
+    ("hi-IN", "हिन्दी (भारत)"),
+    ("hr", "Hrvatski"),
+    ("hsb", "Hornjoserbsce"),
+    ("hu", "magyar"),
+    ("hy-AM", "հայերեն"),
+    ("ia", "Interlingua"),
+    ("id", "Indonesia"),
+    ("is", "íslenska"),
+    ("it", "Italiano"),
+    ("ja", "日本語"),
+    ("ka", "ქართული"),
+    ("kab", "Taqbaylit"),
+    ("kk", "Қазақ"),
+    ("km", "ខ្មែរ"),
+    ("kn", "ಕನ್ನಡ"),
+    ("ko", "한국어"),
+    ("lij", "Ligure"),
+    ("lt", "Lietuvių"),
+    ("lv", "Latviešu"),
+    ("mk", "Македонски"),
+    ("mr", "मराठी"),
+    ("ms", "Melayu"),
+    ("my", "မြန်မာဘာသာ"),
+    ("nb-NO", "Norsk (bokmål)"),
+    ("ne-NP", "नेपाली"),
+    ("nl", "Nederlands"),
+    ("nn-NO", "Norsk (nynorsk)"),
+    ("oc", "Occitan"),
+    ("pa-IN", "ਪੰਜਾਬੀ (ਭਾਰਤ)"),
+    ("pl", "Polski"),
+    ("pt-BR", "Português (do Brasil)"),
+    ("pt-PT", "Português (Europeu)"),
+    ("rm", "Rumantsch"),
+    ("ro", "Română"),
+    ("ru", "Русский"),
+    ("sc", "Sardu"),
+    ("si", "සිංහල"),
+    ("sk", "slovenčina"),
+    ("skr", "سرائیکی"),
+    ("sl", "Slovenščina"),
+    ("son", "Soŋay"),
+    ("sq", "Shqip"),
+    ("sr", "Српски"),
+    ("sv-SE", "Svenska"),
+    ("szl", "Ślōnsko"),
+    ("ta", "தமிழ்"),
+    ("te", "తెలుగు"),
+    ("tg", "Тоҷикӣ"),
+    ("th", "ไทย"),
+    ("tl", "Tagalog"),
+    ("tr", "Türkçe"),
+    ("trs", "Triqui"),
+    ("uk", "Українська"),
+    ("ur", "اُردو"),
+    ("uz", "Oʻzbek"),
+    ("vi", "Tiếng Việt"),
+    ("xh", "isiXhosa"),
+    ("zh-CN", "中文 (简体)"),
+    ("zh-TW", "正體中文 (繁體)"),
+];
+
+fn download_json(downloader: &mut Downloader, url: &str) -> Result<serde_json::Value,Error> {
+    let contents = downloader.download_to_string(url)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/* Locales built for mozilla-central, one per line */
+pub const NIGHTLY_LOCALES_URL: &str = "https://hg.mozilla.org/mozilla-central/raw-file/default/browser/locales/all-locales";
+
+/* Locales that only exist on macOS, there is no linux build to download */
+const MACOS_LOCALES: &[&str] = &["ja-JP-mac"];
+
+fn is_linux_locale(code: &str) -> bool {
+    !MACOS_LOCALES.contains(&code)
+}
+
+/* Whether firefox is built for a locale, as far as we know without asking the network */
+pub fn is_known_locale(code: &str) -> bool {
+    is_linux_locale(code) && FALLBACK_LANGUAGES.iter().any(|language| language.0 == code)
+}
+
+/* Linux locales of an all-locales file, one per line */
+fn parse_all_locales(contents: &str) -> Vec<String> {
+    let mut result: Vec<String> = contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && is_linux_locale(line))
+        .map(String::from)
+        .collect();
+    if !result.iter().any(|code| code == "en-US") {
+        result.push(String::from("en-US"));
+    }
+    result
+}
+
+/*
+    Locales shipped for a channel. Release and ESR are listed in
+    firefox_primary_builds.json, beta and devedition in
+    firefox_beta_builds.json, nightly builds everything in all-locales.
+*/
+fn channel_locales(downloader: &mut Downloader, base_url: &str, channel: &str) -> Result<Vec<String>,Error> {
+    let builds = match channel {
+        "release" | "esr" => "firefox_primary_builds.json",
+        "beta" | "devedition" => "firefox_beta_builds.json",
+        "nightly" => {
+            let contents = downloader.download_to_string(NIGHTLY_LOCALES_URL)?;
+            return Ok(parse_all_locales(&contents));
+        }
+        _ => return Err(Error::Config(format!("Unknown channel: {}", channel))),
+    };
+
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), builds);
+    let builds = download_json(downloader, &url)?;
+    let builds = builds.as_object()
+        .ok_or_else(|| Error::new(&format!("Invalid {}", url)))?;
+    Ok(builds.keys().filter(|code| is_linux_locale(code)).cloned().collect())
+}
+
+/*
+    Shipped locales of a channel, named after languages.json
+*/
+fn download_languages(downloader: &mut Downloader, base_url: &str, channel: &str) -> Result<Vec<(String,String)>,Error> {
+    let locales = channel_locales(downloader, base_url, channel)?;
+    let names = download_json(downloader, &format!("{}/languages.json", base_url.trim_end_matches('/')))
+        .unwrap_or(serde_json::Value::Null);
+
+    let result: Vec<(String,String)> = locales.iter()
+        .map(|code| {
+            let name = names[code.as_str()]["native"].as_str()
+                .or_else(|| FALLBACK_LANGUAGES.iter().find(|elem| elem.0 == code).map(|elem| elem.1))
+                .unwrap_or(code);
+            (code.clone(), String::from(name))
+        })
+        .collect();
+    if result.is_empty() {
+        return Err(Error::new(&format!("No languages for {}", channel)));
+    }
+    Ok(result)
+}
+
+/*
+    (code, native name) of the locales available for a channel, with en-US
+    first and the others sorted by name. Never fails: falls back to a
+    built-in list.
+*/
+pub fn languages(downloader: &mut Downloader, base_url: &str, channel: &str) -> Vec<(String,String)> {
+    let mut result = download_languages(downloader, base_url, channel).unwrap_or_else(|_| fallback_languages());
+
+    result.sort_by_key(|(code, name)| (code != "en-US", name.to_lowercase()));
+    result
+}
+
+/* The built-in list, sorted like languages() */
+pub fn fallback_languages() -> Vec<(String,String)> {
+    let mut result: Vec<(String,String)> = FALLBACK_LANGUAGES.iter()
+        .map(|(code, name)| (String::from(*code), String::from(*name)))
+        .collect();
+    result.sort_by_key(|(code, name)| (code != "en-US", name.to_lowercase()));
+    result
+}
+
+/*
+    The user's locales in order of preference, following POSIX precedence:
+    the LANGUAGE list (unless the locale is C), then LC_ALL, LC_MESSAGES or
+    LANG, whichever is set first
+*/
+pub fn preferred_locales() -> Vec<String> {
+    locales_from(|name| std::env::var(name).ok())
+}
+
+/* preferred_locales() with the environment variables given by `env` */
+fn locales_from<F>(env: F) -> Vec<String>
+where
+    F: Fn(&str) -> Option<String>
+{
+    let var = |name: &str| env(name).filter(|value| !value.is_empty());
+    let locale = var("LC_ALL")
+        .or_else(|| var("LC_MESSAGES"))
+        .or_else(|| var("LANG"));
+
+    let mut result = Vec::new();
+    let is_c = |locale: &str| locale == "C" || locale == "POSIX" || locale.starts_with("C.");
+    if locale.as_deref().is_some_and(|locale| !is_c(locale)) {
+        if let Some(language) = var("LANGUAGE") {
+            result.extend(language.split(':').filter(|elem| !elem.is_empty()).map(String::from));
+        }
+    }
+    if let Some(locale) = locale {
+        result.push(locale);
+    }
+    result
+}
+
+/*
+    sr_RS.UTF-8@latin => ("sr", "RS", "latin")
+*/
+fn parse_locale(locale: &str) -> Option<(String,String,String)> {
+    let (locale, modifier) = locale.split_once('@').unwrap_or((locale, ""));
+    let locale = locale.split('.').next().unwrap_or_default();
+    if locale.is_empty() || locale == "C" || locale == "POSIX" {
+        return None;
+    }
+
+    let mut parts = locale.split(['_', '-']);
+    let language = parts.next().unwrap_or_default().to_lowercase();
+    let region = parts.next().unwrap_or_default().to_uppercase();
+    Some((language, region, modifier.to_lowercase()))
+}
+
+/*
+    Mozilla locale to use for a language when there is none for the region
+*/
+fn default_locale(language: &str, region: &str) -> Option<&'static str> {
+    match (language, region) {
+        ("en", "AU") | ("en", "NZ") | ("en", "IE") | ("en", "ZA") | ("en", "IN") => Some("en-GB"),
+        ("en", _) => Some("en-US"),
+        ("es", "") | ("es", "ES") => Some("es-ES"),
+        /* es-MX is Mozilla's Latin American Spanish */
+        ("es", _) => Some("es-MX"),
+        ("pt", _) => Some("pt-PT"),
+        ("zh", "TW") | ("zh", "HK") | ("zh", "MO") => Some("zh-TW"),
+        ("zh", _) => Some("zh-CN"),
+        ("nb", _) | ("no", _) => Some("nb-NO"),
+        ("nn", _) => Some("nn-NO"),
+        ("sv", _) => Some("sv-SE"),
+        ("ga", _) => Some("ga-IE"),
+        ("gu", _) => Some("gu-IN"),
+        ("hi", _) => Some("hi-IN"),
+        ("pa", _) => Some("pa-IN"),
+        ("ne", _) => Some("ne-NP"),
+        ("hy", _) => Some("hy-AM"),
+        ("fy", _) => Some("fy-NL"),
+        _ => None,
+    }
+}
+
+fn find_locale<'a>(available: &'a [(String,String)], code: &str) -> Option<&'a str> {
+    available.iter()
+        .find(|elem| elem.0.eq_ignore_ascii_case(code))
+        .map(|elem| elem.0.as_str())
+}
+
+fn match_locale<'a>(locale: &str, available: &'a [(String,String)]) -> Option<&'a str> {
+    let (language, region, modifier) = parse_locale(locale)?;
+
+    /* ca@valencia => ca-valencia */
+    if !modifier.is_empty() {
+        if let Some(found) = find_locale(available, &format!("{}-{}", language, modifier)) {
+            return Some(found);
+        }
+    }
+
+    if !region.is_empty() {
+        if let Some(found) = find_locale(available, &format!("{}-{}", language, region)) {
+            return Some(found);
+        }
+    }
+
+    if let Some(found) = find_locale(available, &language) {
+        return Some(found);
+    }
+
+    if let Some(found) = default_locale(&language, &region).and_then(|code| find_locale(available, code)) {
+        return Some(found);
+    }
+
+    /* Any other variant of the language that has a linux build */
+    let prefix = format!("{}-", language);
+    available.iter()
+        .map(|elem| elem.0.as_str())
+        .find(|code| code.to_lowercase().starts_with(&prefix) && is_linux_locale(code))
+}
+
+/*
+    Best available Mozilla locale for a list of POSIX locales in order of
+    preference, e.g. ["pt_BR.UTF-8", "ca@valencia", "es_CO"]
+*/
+pub fn negotiate_language<'a>(preferences: &[String], available: &'a [(String,String)]) -> Option<&'a str> {
+    preferences.iter().find_map(|locale| match_locale(locale, available))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, TestServer};
+
+    fn env(vars: &[(&str, &str)]) -> Vec<String> {
+        locales_from(|name| vars.iter().find(|var| var.0 == name).map(|var| String::from(var.1)))
+    }
+
+    #[test]
+    fn preference_order() {
+        assert_eq!(env(&[ ("LANG", "de_DE.UTF-8") ]), vec![ "de_DE.UTF-8" ]);
+        assert_eq!(env(&[ ("LANG", "de_DE.UTF-8"), ("LC_MESSAGES", "fr_FR.UTF-8") ]), vec![ "fr_FR.UTF-8" ]);
+        assert_eq!(env(&[ ("LANG", "de_DE.UTF-8"), ("LC_MESSAGES", "fr_FR.UTF-8"), ("LC_ALL", "it_IT.UTF-8") ]), vec![ "it_IT.UTF-8" ]);
+        assert_eq!(env(&[ ("LANG", "de_DE.UTF-8"), ("LC_ALL", "") ]), vec![ "de_DE.UTF-8" ]);
+        assert_eq!(env(&[ ("LANG", "pt_BR.UTF-8"), ("LANGUAGE", "pt_BR:pt:en") ]), vec![ "pt_BR", "pt", "en", "pt_BR.UTF-8" ]);
+        /* LANGUAGE is ignored for the C locale */
+        assert_eq!(env(&[ ("LANG", "C.UTF-8"), ("LANGUAGE", "fr") ]), vec![ "C.UTF-8" ]);
+        assert_eq!(env(&[ ("LANGUAGE", "fr") ]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut available = fallback_languages();
+        available.push((String::from("ja-JP-mac"), String::from("日本語")));
+
+        let cases: &[(&[&str], Option<&str>)] = &[
+            (&[ "de_DE.UTF-8" ], Some("de")),
+            (&[ "de_AT.UTF-8" ], Some("de")),
+            (&[ "xx_YY", "fr_CA.UTF-8" ], Some("fr")),
+            (&[ "sr_RS@latin" ], Some("sr")),
+            (&[ "ca_ES.UTF-8@valencia" ], Some("ca-valencia")),
+            (&[ "ca@valencia" ], Some("ca-valencia")),
+            (&[ "ca_ES.UTF-8" ], Some("ca")),
+            (&[ "es_ES.UTF-8" ], Some("es-ES")),
+            (&[ "es" ], Some("es-ES")),
+            (&[ "es_AR.UTF-8" ], Some("es-AR")),
+            (&[ "es_MX.UTF-8" ], Some("es-MX")),
+            (&[ "es_CO.UTF-8" ], Some("es-MX")),
+            (&[ "pt_BR.UTF-8" ], Some("pt-BR")),
+            (&[ "pt_PT.UTF-8" ], Some("pt-PT")),
+            (&[ "pt" ], Some("pt-PT")),
+            (&[ "ja_JP.UTF-8" ], Some("ja")),
+            (&[ "en_AU.UTF-8" ], Some("en-GB")),
+            (&[ "en" ], Some("en-US")),
+            (&[ "zh_HK.UTF-8" ], Some("zh-TW")),
+            (&[ "C" ], None),
+            (&[ "xx_YY" ], None),
+        ];
+        for (preferences, expected) in cases {
+            let preferences: Vec<String> = preferences.iter().map(|locale| String::from(*locale)).collect();
+            assert_eq!(negotiate_language(&preferences, &available), *expected, "{:?}", preferences);
+        }
+    }
+
+    #[test]
+    fn all_locales() {
+        let locales = parse_all_locales("ach\naf\n\nja\nja-JP-mac\nzh-TW\n");
+        assert_eq!(locales, vec![ "ach", "af", "ja", "zh-TW", "en-US" ]);
+    }
+
+    #[test]
+    fn channel_locales_leave_out_macos() {
+        let builds = r#"{ "de": {}, "en-US": {}, "ja": {}, "ja-JP-mac": {} }"#;
+        let server = TestServer::start(move |path, _| match path {
+            "/1.0/firefox_primary_builds.json" | "/1.0/firefox_beta_builds.json" => response("200 OK", &[], builds.as_bytes()),
+            _ => response("404 Not Found", &[], b""),
+        });
+        let cachedir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let base_url = format!("{}/1.0", server.url);
+        for channel in [ "release", "beta" ] {
+            assert_eq!(channel_locales(&mut downloader, &base_url, channel).unwrap(), vec![ "de", "en-US", "ja" ]);
+        }
+    }
+
+    #[test]
+    fn never_picks_ja_jp_mac() {
+        let available = vec![
+            (String::from("en-US"), String::from("English (US)")),
+            (String::from("ja-JP-mac"), String::from("日本語")),
+        ];
+        assert_eq!(negotiate_language(&[ String::from("ja_JP.UTF-8") ], &available), None);
+    }
+}
@@ -5,47 +5,203 @@ use chrono::{Utc, Duration, DateTime, NaiveDateTime};
 use json::JsonValue;
 use json::object;
 use reqwest::blocking::Response;
-use reqwest::blocking::Client;
-use reqwest::header::{ HeaderMap, HeaderValue} ;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::{Certificate, Proxy};
+use reqwest::header::{ HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE };
+use reqwest::redirect::Policy;
+use reqwest::StatusCode;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde::{Deserialize, Serialize};
+use fs2::FileExt;
+use crate::config::NetworkConfig;
 
-#[derive(Debug, Clone)]
-pub struct Error {
-    message: String,
+pub mod architecture;
+pub mod archive;
+pub mod config;
+pub mod install_info;
+pub mod languages;
+pub mod mirror;
+pub mod product_details;
+pub mod verify;
+
+#[cfg(test)]
+mod test_support;
+
+#[derive(Debug)]
+pub enum Error {
+    /* The server could not be reached */
+    Network(reqwest::Error),
+    /* The server answered with an error status */
+    Http { url: String, status: u16 },
+    /* The connection broke in the middle of a download */
+    Interrupted { bytes: i64, source: std::io::Error },
+    /* File system error, with the file involved when known */
+    Io { path: Option<PathBuf>, source: std::io::Error },
+    /* Unusable cache index or cache entry */
+    Cache(String),
+    /* Invalid or unsafe archive */
+    Extraction(String),
+    /* Stopped by the user */
+    Cancelled,
+    /* Checksum or signature mismatch */
+    Verification(String),
+    /* Firefox could not be started */
+    Launch(std::io::Error),
+    /* A program we rely on, e.g. gpg, could not be started */
+    Tool { program: String, source: std::io::Error },
+    /* Invalid configuration, or a choice forbidden by it */
+    Config(String),
+    /* Malformed JSON document */
+    Json(serde_json::Error),
+    Other(String),
 }
 
 impl Error {
     pub fn new(message: &str) -> Self {
-        Self { message: String::from(message) }
+        Error::Other(String::from(message))
+    }
+
+    pub fn io(path: &Path, source: std::io::Error) -> Self {
+        Error::Io { path: Some(path.to_path_buf()), source }
+    }
+
+    /*
+        Whether trying again later may succeed
+    */
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::Interrupted { .. } => true,
+            Error::Http { status, .. } => is_transient_status(*status),
+            _ => false,
+        }
     }
 }
 
+fn is_transient_status(status: u16) -> bool {
+    status >= 500 || status == 408 || status == 429
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+        match self {
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Http { url, status } => {
+                let reason = StatusCode::from_u16(*status).ok()
+                    .and_then(|status| status.canonical_reason())
+                    .unwrap_or_default();
+                write!(f, "Server returned {} {} for {}", status, reason, url)
+            }
+            Error::Interrupted { bytes, source } => write!(f, "Download interrupted after {} bytes: {}", bytes, source),
+            Error::Io { path: Some(path), source } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Launch(e) => write!(f, "Cannot launch firefox: {}", e),
+            Error::Tool { program, source } => write!(f, "Cannot run {}: {}", program, source),
+            Error::Json(e) => write!(f, "Invalid JSON document: {}", e),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::Cache(message)
+                | Error::Extraction(message)
+                | Error::Verification(message)
+                | Error::Config(message)
+                | Error::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            Error::Interrupted { source, .. } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            Error::Launch(e) => Some(e),
+            Error::Tool { source, .. } => Some(source),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self { message: e.to_string() }
+        Error::Io { path: None, source: e }
     }
 }
 
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self {
-        Self { message: e.to_string() }
+        match (e.status(), e.url()) {
+            (Some(status), Some(url)) => Error::Http { url: url.to_string(), status: status.as_u16() },
+            _ => Error::Network(e),
+        }
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        Self { message: e.to_string() }
+        Error::Json(e)
+    }
+}
+
+/* Used when the server does not say how long a response may be cached */
+const DEFAULT_TEXT_MAX_AGE: i64 = 3600;
+const DEFAULT_FILE_MAX_AGE: i64 = 30 * 24 * 3600;
+const MAX_REDIRECTS: usize = 10;
+
+pub const USER_AGENT: &str = concat!("firefox-user-installer/", env!("CARGO_PKG_VERSION"));
+
+/* Least recently used downloads are evicted when the cache grows above this */
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 512 * 1024 * 1024;
+
+/*
+    Version of the cache index format. 1 (no version field) cached files
+    under the url they were requested from, 2 under the url they were
+    redirected to.
+*/
+const CACHE_VERSION: u64 = 2;
+
+/* Longest wait between two attempts, however many retries are configured */
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/* Orphaned files younger than this may belong to a download in progress */
+const SWEEP_GRACE_PERIOD: u64 = 3600;
+
+/* Prefixes of the files created in the cache directory */
+const CACHE_FILE_PREFIXES: &[&str] = &["download_", "c_", "cache.json."];
+
+/*
+    Shared flag telling a download or an extraction running in another
+    thread to stop. It is checked between chunks, so the operation stops
+    within one read and returns Error::Cancelled after cleaning up.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /* Err(Error::Cancelled) once cancelled, to bail out with `?` */
+    pub fn check(&self) -> Result<(),Error> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -55,43 +211,442 @@ struct CacheEntry {
     date: i64,
     max_age: i64,
     filename: String,
+    #[serde(default)]
+    location: String,
+    /* Partial downloads are kept until they can be resumed */
+    #[serde(default = "default_complete")]
+    complete: bool,
+    #[serde(default)]
+    offset: i64,
+    #[serde(default)]
+    etag: String,
+    #[serde(default)]
+    last_modified: String,
+    #[serde(default)]
+    last_used: i64,
+}
+
+fn default_complete() -> bool {
+    true
 }
 
-// DateTime::from_utc(NaiveDateTime::from_timestamp(obj["date"].as_i64().unwrap(), 0), Utc);
 impl CacheEntry {
-    fn new(cache_file: &str, max_age: i64, filename: &str) -> Self {
+    fn new(cache_file: &str, max_age: i64, filename: &str, location: &str) -> Self {
         Self {
             cache_file : String::from(cache_file),
             date: Utc::now().timestamp(),
-            max_age: max_age,
+            max_age,
             filename: String::from(filename),
+            location: String::from(location),
+            complete: true,
+            offset: 0,
+            etag: String::new(),
+            last_modified: String::new(),
+            last_used: Utc::now().timestamp(),
+        }
+    }
+
+    fn is_fresh(&self) -> bool {
+        Utc::now().timestamp() - self.date < self.max_age
+    }
+
+    /* Entries written by older versions were never marked as used */
+    fn last_used(&self) -> i64 {
+        self.last_used.max(self.date)
+    }
+
+    /*
+        Stale entries are kept if the server can tell us they did not change.
+        Partial ones are resumed with If-Range, which starts over if they did.
+    */
+    fn can_revalidate(&self) -> bool {
+        if self.complete {
+            !self.etag.is_empty() || !self.last_modified.is_empty()
+        } else {
+            self.range_validator().is_some()
+        }
+    }
+
+    /*
+        Validator for If-Range: it only accepts strong etags, so fall back
+        to the last modification date for weak ones
+    */
+    fn range_validator(&self) -> Option<&str> {
+        if !self.etag.is_empty() && !self.etag.starts_with("W/") {
+            Some(&self.etag)
+        } else if !self.last_modified.is_empty() {
+            Some(&self.last_modified)
+        } else {
+            None
         }
     }
 }
 
+/*
+    Where an url redirected to when it was last fetched. Moving aliases like
+    "?product=firefox-latest" are only remembered for as long as the redirect
+    response allows, the content itself is cached under the final url.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Redirect {
+    location: String,
+    date: i64,
+    max_age: i64,
+}
+
+impl Redirect {
+    fn is_fresh(&self) -> bool {
+        Utc::now().timestamp() - self.date < self.max_age
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Cache {
+    #[serde(default)]
+    version: u64,
     cache: HashMap<String,CacheEntry>,
+    #[serde(default)]
+    redirects: HashMap<String,Redirect>,
 }
 
 impl Cache {
     fn new() -> Cache {
-        Cache { cache: HashMap::new() }
+        Cache { version: CACHE_VERSION, cache: HashMap::new(), redirects: HashMap::new() }
+    }
+
+    /*
+        Parse an index of any version, whose files are in `cachedir`. Entries
+        that cannot be understood are dropped one by one rather than losing
+        the whole index.
+    */
+    fn from_json(value: serde_json::Value, cachedir: &Path) -> Cache {
+        let mut result = Cache::new();
+        let version = value["version"].as_u64().unwrap_or(1);
+        if let Some(entries) = value["cache"].as_object() {
+            for (url, entry) in entries {
+                if let Ok(entry) = serde_json::from_value::<CacheEntry>(entry.clone()) {
+                    result.cache.insert(url.clone(), entry);
+                }
+            }
+        }
+        if let Some(redirects) = value["redirects"].as_object() {
+            for (url, redirect) in redirects {
+                if let Ok(redirect) = serde_json::from_value::<Redirect>(redirect.clone()) {
+                    result.redirects.insert(url.clone(), redirect);
+                }
+            }
+        }
+
+        if version < 2 {
+            result.migrate_to_redirect_keys(cachedir);
+        }
+        result
+    }
+
+    /*
+        Move entries keyed by an alias to the url it redirected to. Entries
+        that don't know where they came from may be a stale alias, e.g. an
+        old "?product=firefox-latest-ssl" download, and are deleted.
+    */
+    fn migrate_to_redirect_keys(&mut self, cachedir: &Path) {
+        let unknown: Vec<String> = self.cache.iter()
+            .filter(|(_, entry)| entry.location.is_empty())
+            .map(|(url, _)| url.clone())
+            .collect();
+        for url in unknown {
+            if let Some(entry) = self.cache.remove(&url) {
+                std::fs::remove_file(cachedir.join(&entry.cache_file)).ok();
+            }
+        }
+
+        let aliased: Vec<String> = self.cache.iter()
+            .filter(|(url, entry)| Downloader::normalize_url(&entry.location) != **url)
+            .map(|(url, _)| url.clone())
+            .collect();
+        for url in aliased {
+            if let Some(entry) = self.cache.remove(&url) {
+                let location = Downloader::normalize_url(&entry.location);
+                self.redirects.insert(url, Redirect { location: location.clone(), date: entry.date, max_age: 0 });
+                self.cache.insert(location, entry);
+            }
+        }
+    }
+
+    fn apply(&mut self, changes: &Changes) {
+        for (url, entry) in changes.entries.iter() {
+            match entry {
+                Some(entry) => self.cache.insert(url.clone(), entry.clone()),
+                None => self.cache.remove(url),
+            };
+        }
+        for (url, redirect) in changes.redirects.iter() {
+            match redirect {
+                Some(redirect) => self.redirects.insert(url.clone(), redirect.clone()),
+                None => self.redirects.remove(url),
+            };
+        }
     }
 }
 
+/*
+    Changes made since the index was last written. They are replayed on top
+    of the index on disk, so that other instances' changes are not lost.
+*/
+#[derive(Default)]
+struct Changes {
+    entries: HashMap<String,Option<CacheEntry>>,
+    redirects: HashMap<String,Option<Redirect>>,
+}
+
+/* A cached download, as listed by --cache-list */
+pub struct CacheItem {
+    pub url: String,
+    pub filename: String,
+    pub size: u64,
+    pub last_used: i64,
+    /* False for interrupted downloads waiting to be resumed */
+    pub complete: bool,
+    /* False if the server has to be asked whether it is still current */
+    pub fresh: bool,
+}
+
+/* Result of looking up an url, in the cache first and then on the server */
+enum Fetched {
+    /* Fresh, or confirmed unchanged by the server */
+    Cached { key: String, entry: CacheEntry },
+    /* Response from the final url `key`; `offset` is where a resumed download starts */
+    Response { key: String, entry: Option<CacheEntry>, offset: u64, response: Response },
+}
+
 pub struct Downloader {
     cachedir: PathBuf,
     index_file: PathBuf,
     cache: Cache,
+    changes: Changes,
+    max_age: Option<i64>,
+    max_size: u64,
+    client: Client,
+    retries: u32,
+    retry_delay: std::time::Duration,
+    /* Stops waiting between retries of requests made without a CancelToken of their own */
+    cancel: CancelToken,
+    /* Final url of each download made by this instance, cached or not */
+    locations: HashMap<String,String>,
+    /* Cache problems that did not prevent downloading, not reported yet */
+    warnings: Vec<Error>,
+}
+
+/*
+    Settings of a Downloader. Without a proxy, the http_proxy, https_proxy
+    and no_proxy environment variables are used.
+*/
+pub struct DownloaderBuilder {
+    cachedir: PathBuf,
+    connect_timeout: std::time::Duration,
+    timeout: std::time::Duration,
+    retries: u32,
+    retry_delay: std::time::Duration,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    user_agent: String,
+    max_age: Option<i64>,
+    max_size: u64,
+    cancel: CancelToken,
+}
+
+impl DownloaderBuilder {
+    pub fn connect_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /* How long to wait for the server, while connected */
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /* Retries are spaced by `delay`, doubled after each attempt up to MAX_RETRY_DELAY */
+    pub fn retries(mut self, retries: u32, delay: std::time::Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /* Token that interrupts the retries of download_to_string() */
+    pub fn cancel(mut self, cancel: &CancelToken) -> Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(String::from(proxy));
+        self
+    }
+
+    /* Comma-separated hosts or domains not to reach through the proxy */
+    pub fn no_proxy(mut self, no_proxy: &str) -> Self {
+        self.no_proxy = Some(String::from(no_proxy));
+        self
+    }
+
+    /* PEM file with certificates to trust in addition to the system ones */
+    pub fn ca_bundle(mut self, path: &Path) -> Self {
+        self.ca_bundle = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = String::from(user_agent);
+        self
+    }
+
+    /* See Downloader::set_max_age */
+    pub fn max_age(mut self, max_age: Option<i64>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /* Apply the settings of the installer configuration */
+    pub fn network(mut self, config: &NetworkConfig) -> Self {
+        if let Some(proxy) = &config.proxy {
+            self = self.proxy(proxy);
+        }
+        if let Some(no_proxy) = &config.no_proxy {
+            self = self.no_proxy(no_proxy);
+        }
+        if let Some(ca_bundle) = &config.ca_bundle {
+            self = self.ca_bundle(ca_bundle);
+        }
+        if let Some(timeout) = config.connect_timeout {
+            self = self.connect_timeout(std::time::Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = config.timeout {
+            self = self.timeout(std::time::Duration::from_secs(timeout));
+        }
+        if let Some(retries) = config.retries {
+            self.retries = retries;
+        }
+        self
+    }
+
+    /* An explicit proxy, bypassed for the hosts matching no_proxy (or $no_proxy) */
+    fn build_proxy(&self, proxy: &str) -> Result<Proxy,Error> {
+        let proxy = reqwest::Url::parse(proxy)
+            .map_err(|e| Error::Config(format!("Invalid proxy {}: {}", proxy, e)))?;
+        let no_proxy: Vec<String> = self.no_proxy.clone()
+            .or_else(|| std::env::var("NO_PROXY").ok())
+            .or_else(|| std::env::var("no_proxy").ok())
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().trim_start_matches('.').to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        Ok(Proxy::custom(move |url| {
+            let host = url.host_str().unwrap_or_default().to_lowercase();
+            let bypass = no_proxy.iter().any(|pattern| {
+                pattern == "*" || host == *pattern || host.ends_with(&format!(".{}", pattern))
+            });
+            if bypass {
+                None
+            } else {
+                Some(proxy.clone())
+            }
+        }))
+    }
+
+    /* The certificates of a PEM bundle, which may hold several of them */
+    fn load_certificates(path: &Path) -> Result<Vec<Certificate>,Error> {
+        const END: &str = "-----END CERTIFICATE-----";
+        let contents = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let invalid = |e: reqwest::Error| Error::Config(format!("Invalid CA bundle {}: {}", path.display(), e));
+        let mut result = Vec::new();
+        for block in contents.split_inclusive(END).filter(|block| block.contains(END)) {
+            result.push(Certificate::from_pem(block.trim().as_bytes()).map_err(invalid)?);
+        }
+        if result.is_empty() {
+            return Err(Error::Config(format!("No certificate in {}", path.display())));
+        }
+        Ok(result)
+    }
+
+    pub fn build(self) -> Result<Downloader,Error> {
+        /* Redirects are followed by Downloader::fetch, to cache under the final url */
+        let mut client = Client::builder()
+            .redirect(Policy::none())
+            .user_agent(self.user_agent.as_str())
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        if let Some(proxy) = &self.proxy {
+            client = client.proxy(self.build_proxy(proxy)?);
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            for certificate in Self::load_certificates(ca_bundle)? {
+                client = client.add_root_certificate(certificate);
+            }
+        }
+
+        let index_file: PathBuf =  [ &self.cachedir, &PathBuf::from("cache.json") ].iter().collect();
+        let (cache, warnings) = match Downloader::load_cache(&index_file) {
+            Ok(cache) => (cache, Vec::new()),
+            Err(e) => (Cache::new(), vec![e]),
+        };
+        Ok(Downloader {
+            cachedir: self.cachedir,
+            index_file,
+            cache,
+            changes: Changes::default(),
+            max_age: self.max_age,
+            max_size: self.max_size,
+            client: client.build()?,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            cancel: self.cancel,
+            locations: HashMap::new(),
+            warnings,
+        })
+    }
 }
 
 impl Downloader {
 
-    pub fn new(cachedir: &PathBuf) -> Self {
-        let index_file: PathBuf =  [ cachedir, &PathBuf::from("cache.json") ].iter().collect();
-        let cache = Self::load_cache(&index_file);
-        Self { cachedir: cachedir.clone(), index_file, cache }
+    pub fn new(cachedir: &Path) -> Result<Self,Error> {
+        Self::builder(cachedir).build()
+    }
+
+    pub fn builder(cachedir: &Path) -> DownloaderBuilder {
+        DownloaderBuilder {
+            cachedir: cachedir.to_path_buf(),
+            connect_timeout: std::time::Duration::from_secs(30),
+            timeout: std::time::Duration::from_secs(60),
+            retries: 3,
+            retry_delay: std::time::Duration::from_secs(1),
+            proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
+            user_agent: String::from(USER_AGENT),
+            max_age: None,
+            max_size: DEFAULT_MAX_CACHE_SIZE,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /*
+        Cache downloads for `max_age` seconds whatever the server says, or
+        follow Cache-Control and Expires again with None
+    */
+    pub fn set_max_age(&mut self, max_age: Option<i64>) {
+        self.max_age = max_age;
+    }
+
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
     }
     
     fn normalize_url(url: &str) -> String {
@@ -102,165 +657,635 @@ impl Downloader {
         url
     }
 
-    fn load_cache(index_file: &Path) -> Cache {
-        let mut result = Cache::new();
+    /*
+        A corrupt index is kept aside as cache.json.corrupt, its files are
+        then reclaimed by the next sweep
+    */
+    fn load_cache(index_file: &Path) -> Result<Cache,Error> {
+        let f = match File::open(index_file) {
+            Ok(f) => f,
+            Err(_) => return Ok(Cache::new()),
+        };
+        match serde_json::from_reader(std::io::BufReader::new(f)) {
+            Ok(value) => Ok(Cache::from_json(value, index_file.parent().unwrap_or_else(|| Path::new(".")))),
+            Err(e) => {
+                std::fs::rename(index_file, index_file.with_extension("json.corrupt")).ok();
+                Err(Error::Cache(format!("Corrupt download cache {}: {}", index_file.display(), e)))
+            }
+        }
+    }
 
-        if let Ok(mut f) = File::open(index_file) {
-            if let Ok(cache) = serde_json::from_reader(f) {
-                result = cache;
+    /* The index on disk, which other instances may have updated since we loaded it */
+    fn reload_cache(&mut self) -> Cache {
+        match Self::load_cache(&self.index_file) {
+            Ok(cache) => cache,
+            Err(e) => {
+                self.warn(e);
+                Cache::new()
             }
         }
+    }
 
-        result
+    /* Same problem reported once */
+    fn warn(&mut self, warning: Error) {
+        let message = warning.to_string();
+        if !self.warnings.iter().any(|e| e.to_string() == message) {
+            self.warnings.push(warning);
+        }
+    }
+
+    /*
+        Cache problems met since the last call. The cache is only an
+        optimization, so they don't make downloads fail, but they are worth
+        telling the user about.
+    */
+    pub fn take_warnings(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /* Advisory lock serializing index updates between launcher instances */
+    fn lock(&self) -> Result<File,Error> {
+        let path = self.cachedir.join("cache.lock");
+        let f = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::io(&path, e))?;
+        f.lock_exclusive().map_err(|e| Error::io(&path, e))?;
+        Ok(f)
+    }
+
+    /*
+        Merge our changes into the index on disk, and replace it atomically.
+        The cache is only an optimization, so failing to write the index
+        (read-only or full cache directory) is not fatal: the changes are
+        kept and written with the next save.
+    */
+    fn save_cache(&mut self) {
+        if let Err(e) = self.write_index() {
+            self.warn(Error::Cache(format!("Cannot write download cache: {}", e)));
+        }
+    }
+
+    fn write_index(&mut self) -> Result<(),Error> {
+        let lock = self.lock()?;
+        let mut cache = self.reload_cache();
+        cache.apply(&self.changes);
+
+        let mut tmpfile = tempfile::Builder::new()
+            .prefix("cache.json.")
+            .tempfile_in(&self.cachedir)?;
+        serde_json::to_writer(std::io::BufWriter::new(tmpfile.as_file_mut()), &cache)?;
+        tmpfile.as_file().sync_all()?;
+        tmpfile.persist(&self.index_file).map_err(|e| Error::io(&self.index_file, e.error))?;
+
+        self.cache = cache;
+        self.changes = Changes::default();
+        Ok(())
+    }
+
+    fn set_entry(&mut self, url: &str, entry: CacheEntry) -> Option<CacheEntry> {
+        self.changes.entries.insert(String::from(url), Some(entry.clone()));
+        self.cache.cache.insert(String::from(url), entry)
+    }
+
+    fn unset_entry(&mut self, url: &str) -> Option<CacheEntry> {
+        self.changes.entries.insert(String::from(url), None);
+        self.cache.cache.remove(url)
     }
 
-    fn save_cache(index_file: &Path, cache: &Cache) {
-        let mut f = File::create(index_file).expect("Failed to write cache file");
-        serde_json::to_writer(f, cache);
+    fn set_redirect(&mut self, url: &str, redirect: Option<Redirect>) {
+        self.changes.redirects.insert(String::from(url), redirect.clone());
+        match redirect {
+            Some(redirect) => self.cache.redirects.insert(String::from(url), redirect),
+            None => self.cache.redirects.remove(url),
+        };
     }
 
+    /*
+        Entry for `url` if it is fresh or can be revalidated. Expired entries
+        and entries whose file disappeared are dropped.
+    */
     fn get_cache_entry(&mut self, url: &str) -> Option<CacheEntry> {
         let url = Self::normalize_url(url);
         let entry = self.cache.cache.get(&url)?;
-        let now = Utc::now();
-
-        let entry_date = DateTime::from_utc(NaiveDateTime::from_timestamp(entry.date, 0), Utc);
-        let max_age = Duration::seconds(entry.max_age);
-        if Utc::now() - entry_date > max_age {
+        let exists = self.cachedir.join(&entry.cache_file).exists();
+        if exists && (entry.is_fresh() || entry.can_revalidate()) {
+            Some(entry.clone())
+        } else {
             self.remove_cache_entry(&url);
             None
-        } else {
-            Some(entry.clone())
         }
     }
 
-    fn add_cache_entry(&mut self, url: &str, file: &Path, max_age: i64, filename: &str) {
+    fn add_cache_entry(&mut self, url: &str, file: &Path, max_age: i64, filename: &str, location: &str) {
+        let url = Self::normalize_url(url);
+        let cache_file = match file.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return
+        };
+        let previous = self.set_entry(&url, CacheEntry::new(cache_file, max_age, filename, location));
+        if let Some(previous) = previous.filter(|previous| previous.cache_file != cache_file) {
+            std::fs::remove_file(self.cachedir.join(previous.cache_file)).ok();
+        }
+        self.save_cache();
+    }
+
+    fn update_cache_entry<F>(&mut self, url: &str, f: F)
+    where
+        F: FnOnce(&mut CacheEntry)
+    {
         let url = Self::normalize_url(url);
-        let cache_file = String::from(file.file_name().unwrap().to_str().unwrap());
-        self.cache.cache.insert(url, CacheEntry::new(&cache_file, max_age, filename));
-        Self::save_cache(&self.index_file, &self.cache);
+        if let Some(mut entry) = self.cache.cache.get(&url).cloned() {
+            f(&mut entry);
+            self.set_entry(&url, entry);
+            self.save_cache();
+        }
     }
 
-    fn remove_cache_entry(&mut self, url: &str) {
+    /* Returns how many bytes were freed */
+    fn remove_cache_entry(&mut self, url: &str) -> u64 {
         let url = Self::normalize_url(url);
-        let entry = self.cache.cache.remove(&url);
-        if let Some(entry) = entry {
-            let file = Path::new(&self.cachedir).join(entry.cache_file);
-            std::fs::remove_file(file);
+        let entry = match self.unset_entry(&url) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+        let size = self.file_size(&entry);
+        let file = Path::new(&self.cachedir).join(entry.cache_file);
+        match std::fs::remove_file(&file) {
+            Ok(_) => size,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => {
+                self.warn(Error::io(&file, e));
+                0
+            }
         }
     }
 
+    /* Where `url` last redirected to, or the url itself */
+    fn resolve(&self, url: &str, fresh_only: bool) -> String {
+        match self.cache.redirects.get(&Self::normalize_url(url)) {
+            Some(redirect) if !fresh_only || redirect.is_fresh() => redirect.location.clone(),
+            _ => String::from(url)
+        }
+    }
+
+    /*
+        Final url (after redirects) a cached download was fetched from
+    */
+    pub fn cached_location(&mut self, url: &str) -> Option<String> {
+        if Self::local_path(url).is_some() {
+            return Some(String::from(url));
+        }
+        let key = self.resolve(url, false);
+        let entry = self.cache.cache.get(&Self::normalize_url(&key))?;
+        if entry.location.is_empty() {
+            None
+        } else {
+            Some(entry.location.clone())
+        }
+    }
+
+    /*
+        Final url the last download of `url` by this instance came from. Also
+        known when the download could not be cached.
+    */
+    pub fn location(&self, url: &str) -> Option<String> {
+        self.locations.get(&Self::normalize_url(url)).cloned()
+    }
+
+    /*
+        Drop a cached download, e.g. because it failed verification
+    */
+    pub fn evict(&mut self, url: &str) {
+        let key = self.resolve(url, false);
+        self.set_redirect(&Self::normalize_url(url), None);
+        self.remove_cache_entry(&key);
+        self.save_cache();
+    }
+
     fn get_filename(r: &Response) -> Option<String> {
-        match r
-            .url()
+        r.url()
             .path_segments()
-            .and_then(|segments| segments.last())
-            .and_then(|name| if name.is_empty() { None } else { Some(name) }) {
-                None => None,
-                Some(s) => Some(String::from(s))
-        }
-    }
-
-    pub fn download_to_string(&mut self, url: &str) -> Option<String> {
-        let cache_entry = self.get_cache_entry(url);
-        if let Some(cache_entry) = cache_entry {
-            let path = Path::new(&self.cachedir).join(&cache_entry.cache_file);
-            match File::open(path) {
-                Ok(mut f) => {
-                    let mut contents = String::new();
-                    match f.read_to_string(&mut contents) {
-                        Ok(_) => {
-                            return Some(contents);
-                        }
-
-                        Err(_) => {
-                            self.remove_cache_entry(url);
-                        }
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+    }
+
+    fn cache_control(headers: &HeaderMap) -> Vec<String> {
+        headers.get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_lowercase())
+            .collect()
+    }
+
+    /*
+        How long a response may be cached according to its Cache-Control
+        (no-store is treated like no-cache: keep, but always revalidate)
+        or Expires headers, minus the time it already spent in other caches
+    */
+    fn header_max_age(headers: &HeaderMap) -> Option<i64> {
+        let directives = Self::cache_control(headers);
+        if directives.iter().any(|directive| directive == "no-store" || directive == "no-cache") {
+            return Some(0);
+        }
+
+        let age = Self::header_string_from(headers, AGE).parse::<i64>().unwrap_or(0);
+        let max_age = directives.iter()
+            .find_map(|directive| directive.strip_prefix("max-age="))
+            .and_then(|value| value.trim_matches('"').parse::<i64>().ok());
+        if let Some(max_age) = max_age {
+            return Some((max_age - age).max(0));
+        }
+
+        /* An invalid Expires means already expired */
+        let expires = headers.get(EXPIRES)?;
+        let date = DateTime::parse_from_rfc2822(&Self::header_string_from(headers, DATE))
+            .map(|date| date.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+        match expires.to_str().ok().and_then(|expires| DateTime::parse_from_rfc2822(expires).ok()) {
+            Some(expires) => Some(((expires.with_timezone(&Utc) - date).num_seconds() - age).max(0)),
+            None => Some(0)
+        }
+    }
+
+    /* Caller override first, then the response headers, then `default` */
+    fn max_age_for(&self, headers: &HeaderMap, default: i64) -> i64 {
+        self.max_age
+            .or_else(|| Self::header_max_age(headers))
+            .unwrap_or(default)
+    }
+
+    /*
+        Follow redirects by hand, so that content is cached under the url it
+        actually comes from, and send validators (or a Range for partial
+        downloads) for whatever is in the cache at the final url
+    */
+    fn fetch(&mut self, url: &str, cancel: &CancelToken) -> Result<Fetched,Error> {
+        let mut key = self.resolve(url, true);
+        let mut redirect_max_age: Option<i64> = None;
+
+        for _ in 0..MAX_REDIRECTS {
+            let entry = self.get_cache_entry(&key);
+            let mut req = self.client.get(&key);
+            let mut offset = 0u64;
+            match &entry {
+                Some(entry) if entry.complete && entry.is_fresh() => {
+                    self.update_cache_entry(&key, |cached| cached.last_used = Utc::now().timestamp());
+                    self.record_redirect(url, &key, redirect_max_age);
+                    return Ok(Fetched::Cached { key, entry: entry.clone() });
+                }
+                Some(entry) if entry.complete => {
+                    if !entry.etag.is_empty() {
+                        req = req.header(IF_NONE_MATCH, &entry.etag);
+                    }
+                    if !entry.last_modified.is_empty() {
+                        req = req.header(IF_MODIFIED_SINCE, &entry.last_modified);
+                    }
+                }
+                Some(entry) => {
+                    let metadata = std::fs::metadata(self.cachedir.join(&entry.cache_file));
+                    if let (Ok(metadata), Some(validator)) = (metadata, entry.range_validator()) {
+                        offset = metadata.len().min(entry.offset as u64);
+                        req = req
+                            .header(RANGE, format!("bytes={}-", offset))
+                            .header(IF_RANGE, validator);
                     }
                 }
+                None => {}
+            }
 
-                Err(_) => {
-                    self.remove_cache_entry(url);
+            let response = self.send(req, cancel)?;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                if let Some(mut entry) = entry {
+                    entry.date = Utc::now().timestamp();
+                    entry.last_used = entry.date;
+                    /* A 304 without caching headers keeps the original policy */
+                    entry.max_age = self.max_age_for(response.headers(), entry.max_age);
+                    let etag = Self::header_string(&response, ETAG);
+                    if !etag.is_empty() {
+                        entry.etag = etag;
+                    }
+                    let refreshed = entry.clone();
+                    self.update_cache_entry(&key, |cached| *cached = refreshed);
+                    self.record_redirect(url, &key, redirect_max_age);
+                    return Ok(Fetched::Cached { key, entry });
                 }
             }
+
+            if response.status().is_redirection() {
+                let status = response.status().as_u16();
+                let location = response.headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| response.url().join(location).ok())
+                    .ok_or_else(|| Error::Http { url: key.clone(), status })?;
+                let max_age = Self::header_max_age(response.headers()).unwrap_or(0);
+                redirect_max_age = Some(redirect_max_age.map_or(max_age, |previous| previous.min(max_age)));
+                key = location.to_string();
+                continue;
+            }
+
+            self.record_redirect(url, &key, redirect_max_age);
+            return Ok(Fetched::Response { key, entry, offset, response });
         }
 
-        let client = Client::new();
-        let mut req = client.get(url);
-        let response = req.send().ok()?;
-        let filename = Self::get_filename(&response);
-        let result = response.text().ok()?;
+        Err(Error::new(&format!("Too many redirects for {}", url)))
+    }
+
+    /*
+        Send a request, retrying with exponential backoff while the server
+        can't be reached or reports a temporary error. Waiting stops as soon
+        as `cancel` is cancelled.
+    */
+    fn send(&self, request: RequestBuilder, cancel: &CancelToken) -> Result<Response,Error> {
+        let mut delay = self.retry_delay;
+        for _ in 0..self.retries {
+            let attempt = match request.try_clone() {
+                Some(attempt) => attempt,
+                None => break,
+            };
+            match attempt.send().map_err(Error::from) {
+                Ok(response) if !is_transient_status(response.status().as_u16()) => return Ok(response),
+                Err(e) if !e.is_transient() => return Err(e),
+                _ => {}
+            }
+            /* In short steps, to notice cancellation */
+            let start = std::time::Instant::now();
+            while start.elapsed() < delay {
+                cancel.check()?;
+                std::thread::sleep(delay.saturating_sub(start.elapsed()).min(std::time::Duration::from_millis(100)));
+            }
+            cancel.check()?;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        Ok(request.send()?)
+    }
+
+    /* `max_age` is None when no redirect was followed */
+    fn record_redirect(&mut self, url: &str, location: &str, max_age: Option<i64>) {
+        if let Some(max_age) = max_age {
+            let redirect = Redirect {
+                location: String::from(location),
+                date: Utc::now().timestamp(),
+                max_age,
+            };
+            self.set_redirect(&Self::normalize_url(url), Some(redirect));
+            self.save_cache();
+        }
+    }
+
+    /* Path of a file:// url. Local files are used as-is, without caching. */
+    fn local_path(url: &str) -> Option<PathBuf> {
+        reqwest::Url::parse(url).ok()
+            .filter(|url| url.scheme() == "file")
+            .and_then(|url| url.to_file_path().ok())
+    }
+
+    /*
+        Names of the entries of a directory: a file:// directory, or an
+        HTML index like the ones of archive.mozilla.org
+    */
+    pub fn list_directory(&mut self, url: &str) -> Result<Vec<String>,Error> {
+        if let Some(path) = Self::local_path(url) {
+            let mut result = Vec::new();
+            for entry in std::fs::read_dir(&path).map_err(|e| Error::io(&path, e))? {
+                let entry = entry.map_err(|e| Error::io(&path, e))?;
+                result.push(entry.file_name().to_string_lossy().into_owned());
+            }
+            result.sort();
+            return Ok(result);
+        }
+
+        let url = format!("{}/", url.trim_end_matches('/'));
+        let index = self.download_to_string(&url)?;
+        let href = regex::Regex::new(r#"href="([^"?#]+)""#).map_err(|e| Error::new(&e.to_string()))?;
+        let mut result: Vec<String> = href.captures_iter(&index)
+            .filter_map(|captures| captures[1].trim_end_matches('/').rsplit('/').next().map(String::from))
+            .filter(|name| !name.is_empty() && name != "..")
+            .collect();
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    pub fn download_to_string(&mut self, url: &str) -> Result<String,Error> {
+        if let Some(path) = Self::local_path(url) {
+            return std::fs::read_to_string(&path).map_err(|e| Error::io(&path, e));
+        }
+
+        let cancel = self.cancel.clone();
+        let (key, response) = match self.fetch(url, &cancel)? {
+            Fetched::Cached { key, entry } => {
+                let path = Path::new(&self.cachedir).join(&entry.cache_file);
+                match std::fs::read_to_string(path) {
+                    Ok(contents) => return Ok(contents),
+                    Err(_) => {
+                        self.remove_cache_entry(&key);
+                        return self.download_to_string(url);
+                    }
+                }
+            }
+            Fetched::Response { key, response, .. } => (key, response)
+        };
+        if !response.status().is_success() {
+            return Err(Error::Http { url: response.url().to_string(), status: response.status().as_u16() });
+        }
+
+        let filename = Self::get_filename(&response).unwrap_or_else(|| String::from("download"));
+        let max_age = self.max_age_for(response.headers(), DEFAULT_TEXT_MAX_AGE);
+        let etag = Self::header_string(&response, ETAG);
+        let last_modified = Self::header_string(&response, LAST_MODIFIED);
+        let result = response.text()?;
+        if max_age > 0 || !etag.is_empty() || !last_modified.is_empty() {
+            if let Err(e) = self.cache_string(&key, &result, &filename, max_age) {
+                self.warn(Error::Cache(format!("Cannot cache {}: {}", url, e)));
+            }
+            self.update_cache_entry(&key, |entry| {
+                entry.etag = etag;
+                entry.last_modified = last_modified;
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn cache_string(&mut self, key: &str, contents: &str, filename: &str, max_age: i64) -> Result<(),Error> {
         let mut cachefile = tempfile::Builder::new()
             .prefix("c_")
             .suffix(".cache")
-            .tempfile_in(&self.cachedir)
-            .ok()?;
-        let mut f = cachefile.as_file_mut();
-        f.write_all(result.as_bytes());
-
-        self.add_cache_entry(url, cachefile.path(), 3600, &filename.unwrap_or(String::from("download")));
-        cachefile.keep();
-
-        Some(String::from(result))
+            .tempfile_in(&self.cachedir)?;
+        cachefile.as_file_mut().write_all(contents.as_bytes())?;
+        let (_, path) = cachefile.keep().map_err(|e| Error::from(e.error))?;
+        self.add_cache_entry(key, &path, max_age, filename, key);
+        self.enforce_max_size(key);
+        Ok(())
     }
 
     pub fn download(&mut self, url: &str, destdir: &Path) -> Result<PathBuf,Error> {
-        self.download_with_progress(url, destdir, |c,t| {})
+        self.download_with_progress(url, destdir, &CancelToken::new(), |c,t| {})
     }
 
     /*
-        This does not use a cache (but we still let the user manually add a cache entry)
+        Downloads are cached for as long as the server allows (30 days if it
+        does not say). An interrupted download is kept in the cache and
+        resumed with a Range request on the next attempt. If the cache
+        directory is not writable, the file is downloaded straight into
+        `destdir` instead.
     */
-    pub fn download_with_progress<F>(&mut self, url: &str, destdir: &Path, progress: F) -> Result<PathBuf,Error>
+    pub fn download_with_progress<F>(&mut self, url: &str, destdir: &Path, cancel: &CancelToken, progress: F) -> Result<PathBuf,Error>
     where
         F: Fn(i64, i64)
     {
-        let cache_entry = self.get_cache_entry(url);
-        if let Some(cache_entry) = cache_entry {
-            let cache_file = Path::new(&self.cachedir).join(&cache_entry.cache_file);
-            if !cache_file.exists() {
-                self.remove_cache_entry(url);
-            }
+        if let Some(path) = Self::local_path(url) {
+            self.locations.insert(Self::normalize_url(url), String::from(url));
+            return Self::copy_with_progress(&path, destdir, cancel, progress);
+        }
 
-            let dest_file = Path::new(destdir).join(&cache_entry.filename);
-            if let Ok(_) = std::fs::copy(cache_file, &dest_file) {
-                return Ok(dest_file);
+        let (key, partial, mut offset, mut response) = match self.fetch(url, cancel)? {
+            Fetched::Cached { key, entry } => {
+                let cache_file = Path::new(&self.cachedir).join(&entry.cache_file);
+                let dest_file = Path::new(destdir).join(&entry.filename);
+                if std::fs::copy(cache_file, &dest_file).is_ok() {
+                    let location = if entry.location.is_empty() { key } else { entry.location };
+                    self.locations.insert(Self::normalize_url(url), location);
+                    return Ok(dest_file);
+                }
+                self.remove_cache_entry(&key);
+                return self.download_with_progress(url, destdir, cancel, progress);
             }
-        }
+            Fetched::Response { key, entry, offset, response } => {
+                let partial = entry
+                    .filter(|entry| !entry.complete)
+                    .map(|entry| (Path::new(&self.cachedir).join(&entry.cache_file), entry));
+                (key, partial, offset, response)
+            }
+        };
 
-        let client = Client::new();
-        let mut req = client.get(url);
-        let mut response = req.send()?;
-        let status = response.status();
         if !response.status().is_success() {
-            return Err(Error::new(&response.status().to_string()));
+            let status = response.status().as_u16();
+            if partial.is_some() && !is_transient_status(status) {
+                /* The server won't resume (416) or the file is gone: start over, or give up */
+                self.remove_cache_entry(&key);
+                self.save_cache();
+                if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                    return self.download_with_progress(url, destdir, cancel, progress);
+                }
+            }
+            return Err(Error::Http { url: response.url().to_string(), status });
         }
+        self.locations.insert(Self::normalize_url(url), response.url().to_string());
 
-        let content_length = response.content_length();
-        let mut tmpfile = tempfile::Builder::new()
-            .prefix("download_")
-            .tempfile_in(&self.cachedir)?;
-                
-        let total = match content_length {
-            Some(len) => len as i64,
+        /* The server sends the whole file when it cannot resume or the file changed */
+        let resumed = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+        let filename = Self::get_filename(&response);
+        let (cache_path, mut outfile, cached) = match partial {
+            Some((cache_file, _)) if resumed => {
+                let mut f = std::fs::OpenOptions::new().write(true).open(&cache_file)?;
+                f.set_len(offset)?;
+                f.seek(SeekFrom::End(0))?;
+                (cache_file, f, true)
+            }
+            _ => {
+                if partial.is_some() {
+                    self.remove_cache_entry(&key);
+                }
+                offset = 0;
+
+                match tempfile::Builder::new().prefix("download_").tempfile_in(&self.cachedir) {
+                    Ok(tmpfile) => {
+                        let (f, path) = tmpfile.keep().map_err(|e| Error::from(e.error))?;
+                        let max_age = self.max_age_for(response.headers(), DEFAULT_FILE_MAX_AGE);
+                        self.add_cache_entry(&key, &path, max_age, filename.as_deref().unwrap_or("download"), response.url().as_str());
+                        let etag = Self::header_string(&response, ETAG);
+                        let last_modified = Self::header_string(&response, LAST_MODIFIED);
+                        self.update_cache_entry(&key, |entry| {
+                            entry.complete = false;
+                            entry.etag = etag;
+                            entry.last_modified = last_modified;
+                        });
+                        (path, f, true)
+                    }
+                    Err(e) => {
+                        self.warn(Error::Cache(format!("Cannot cache {}: {}", url, e)));
+                        let tmpfile = tempfile::Builder::new()
+                            .prefix("download_")
+                            .tempfile_in(destdir)?;
+                        let (f, path) = tmpfile.keep().map_err(|e| Error::from(e.error))?;
+                        (path, f, false)
+                    }
+                }
+            }
+        };
+
+        let total = match response.content_length() {
+            Some(len) => (len + offset) as i64,
             None => -1
         };
-        let mut current = 0i64;
-        let mut outfile = tmpfile.as_file_mut();
+        let mut current = offset as i64;
         let mut buf = [ 0u8; 65536 ];
-        while let Ok(len) = response.read(&mut buf) {
-            if len == 0 {
-                break;
+        let result = loop {
+            if cancel.is_cancelled() {
+                break Ok(());
             }
-            outfile.write_all(&buf[0..len]);
-            current += len as i64;
-            progress(current, total);
+            match response.read(&mut buf) {
+                Ok(0) => {
+                    if total != -1 && current < total {
+                        break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                    }
+                    break Ok(());
+                }
+                Ok(len) => {
+                    if let Err(e) = outfile.write_all(&buf[0..len]) {
+                        break Err(e);
+                    }
+                    current += len as i64;
+                    progress(current, total);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+
+        /* Nobody wants to resume what was cancelled */
+        if cancel.is_cancelled() {
+            if cached {
+                self.remove_cache_entry(&key);
+                self.save_cache();
+            } else {
+                std::fs::remove_file(&cache_path).ok();
+            }
+            return Err(Error::Cancelled);
         }
 
-        let filename = Self::get_filename(&response);
-        let tmp_path = tmpfile.path().to_path_buf();
-        self.add_cache_entry(url, &tmp_path, 30i64 * 24i64 * 3600i64, &filename.clone().unwrap_or(String::from("download")));
-        tmpfile.keep();
+        /* Record how far we got, so that the next attempt can resume from there */
+        let complete = result.is_ok();
+        self.update_cache_entry(&key, |entry| {
+            entry.offset = current;
+            entry.complete = complete;
+        });
+        if let Err(e) = result {
+            if !cached {
+                std::fs::remove_file(&cache_path).ok();
+            }
+            return Err(Error::Interrupted { bytes: current, source: e });
+        }
+
+        let filename = self.cache.cache.get(&key).map(|entry| entry.filename.clone()).or(filename);
+        if !cached {
+            /* Downloaded into destdir already, only the name is left to fix */
+            return match filename {
+                Some(filename) => {
+                    let dest_file = destdir.join(filename);
+                    std::fs::rename(&cache_path, &dest_file)?;
+                    Ok(dest_file)
+                }
+                None => Ok(cache_path)
+            };
+        }
 
         let dest_file = match filename {
             Some(filename) => {
@@ -271,13 +1296,396 @@ impl Downloader {
                 let tmpfile = tempfile::Builder::new()
                     .prefix("download_")
                     .tempfile_in(destdir)?;
-                let result = tmpfile.path().to_path_buf();
-                tmpfile.keep();
+                let (_, result) = tmpfile.keep().map_err(|e| Error::from(e.error))?;
                 result
             }
         };
 
-        std::fs::copy(&tmp_path, &dest_file);
-        Ok(dest_file.to_path_buf())
+        self.enforce_max_size(&key);
+        std::fs::copy(&cache_path, &dest_file)?;
+        Ok(dest_file)
+    }
+
+    fn copy_with_progress<F>(path: &Path, destdir: &Path, cancel: &CancelToken, progress: F) -> Result<PathBuf,Error>
+    where
+        F: Fn(i64, i64)
+    {
+        let mut infile = File::open(path).map_err(|e| Error::io(path, e))?;
+        let total = infile.metadata().map(|metadata| metadata.len() as i64).unwrap_or(-1);
+        let dest_file = destdir.join(path.file_name().unwrap_or_else(|| std::ffi::OsStr::new("download")));
+        let mut outfile = File::create(&dest_file).map_err(|e| Error::io(&dest_file, e))?;
+        let mut current = 0i64;
+        let mut buf = [ 0u8; 65536 ];
+        loop {
+            if cancel.is_cancelled() {
+                drop(outfile);
+                std::fs::remove_file(&dest_file).ok();
+                return Err(Error::Cancelled);
+            }
+            let len = infile.read(&mut buf).map_err(|e| Error::io(path, e))?;
+            if len == 0 {
+                break;
+            }
+            outfile.write_all(&buf[0..len]).map_err(|e| Error::io(&dest_file, e))?;
+            current += len as i64;
+            progress(current, total);
+        }
+        Ok(dest_file)
+    }
+
+    fn file_size(&self, entry: &CacheEntry) -> u64 {
+        std::fs::metadata(self.cachedir.join(&entry.cache_file))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /* Removes the given entries, returns how many bytes were freed */
+    fn remove_cache_entries(&mut self, urls: &[String]) -> u64 {
+        let mut freed = 0;
+        for url in urls {
+            freed += self.remove_cache_entry(url);
+        }
+        if !urls.is_empty() {
+            self.save_cache();
+        }
+        freed
+    }
+
+    /*
+        Drop index entries whose file is gone, and delete files left behind
+        by crashed runs that the index does not know about. Files modified
+        less than `grace_period` seconds ago are left alone, they may belong
+        to another instance that did not record them yet.
+    */
+    fn sweep(&mut self, grace_period: u64) -> u64 {
+        let mut freed = 0;
+        {
+            /* Pick up entries recorded by other instances since we loaded the index */
+            let lock = match self.lock() {
+                Ok(lock) => lock,
+                Err(e) => {
+                    self.warn(e);
+                    return 0;
+                }
+            };
+            let mut cache = self.reload_cache();
+            cache.apply(&self.changes);
+            self.cache = cache;
+
+            let referenced: std::collections::HashSet<&str> = self.cache.cache.values()
+                .map(|entry| entry.cache_file.as_str())
+                .collect();
+            if let Ok(dir) = std::fs::read_dir(&self.cachedir) {
+                for file in dir.flatten() {
+                    let name = file.file_name();
+                    let name = name.to_string_lossy();
+                    let metadata = match file.metadata() {
+                        Ok(metadata) => metadata,
+                        Err(_) => continue,
+                    };
+                    let age = metadata.modified().ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .map(|age| age.as_secs())
+                        .unwrap_or(0);
+                    let orphan = CACHE_FILE_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+                        && !referenced.contains(name.as_ref())
+                        && age >= grace_period;
+                    if orphan && std::fs::remove_file(file.path()).is_ok() {
+                        freed += metadata.len();
+                    }
+                }
+            }
+        }
+
+        let missing: Vec<String> = self.cache.cache.iter()
+            .filter(|(_, entry)| !self.cachedir.join(&entry.cache_file).exists())
+            .map(|(url, _)| url.clone())
+            .collect();
+        self.remove_cache_entries(&missing);
+        freed
+    }
+
+    /*
+        Forget entries whose file is gone and delete files left behind by
+        crashed runs. Walks the whole cache directory, so it is meant to be
+        called once per run, not for every downloader. Returns the bytes freed.
+    */
+    pub fn cleanup(&mut self) -> u64 {
+        self.sweep(SWEEP_GRACE_PERIOD)
+    }
+
+    /* Evict least recently used entries, but never `keep`, until the cache fits */
+    fn enforce_max_size(&mut self, keep: &str) -> u64 {
+        let mut entries: Vec<(String, i64, u64)> = self.cache.cache.iter()
+            .map(|(url, entry)| (url.clone(), entry.last_used(), self.file_size(entry)))
+            .collect();
+        entries.sort_by_key(|(_, last_used, _)| *last_used);
+
+        let keep = Self::normalize_url(keep);
+        let mut total: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        let mut evicted = Vec::new();
+        for (url, _, size) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            if url != keep {
+                total -= size;
+                evicted.push(url);
+            }
+        }
+        self.remove_cache_entries(&evicted)
+    }
+
+    /* Cached downloads, most recently used first */
+    pub fn entries(&self) -> Vec<CacheItem> {
+        let mut result: Vec<CacheItem> = self.cache.cache.iter()
+            .map(|(url, entry)| CacheItem {
+                url: url.clone(),
+                filename: entry.filename.clone(),
+                size: self.file_size(entry),
+                last_used: entry.last_used(),
+                complete: entry.complete,
+                fresh: entry.is_fresh(),
+            })
+            .collect();
+        result.sort_by_key(|item| -item.last_used);
+        result
+    }
+
+    /*
+        Delete expired entries that cannot be revalidated, leftover files and
+        whatever does not fit in the size limit. Returns the bytes freed.
+    */
+    pub fn prune(&mut self) -> u64 {
+        let expired: Vec<String> = self.cache.cache.iter()
+            .filter(|(_, entry)| !entry.is_fresh() && !entry.can_revalidate())
+            .map(|(url, _)| url.clone())
+            .collect();
+        let stale: Vec<String> = self.cache.redirects.iter()
+            .filter(|(_, redirect)| !redirect.is_fresh())
+            .map(|(url, _)| url.clone())
+            .collect();
+        for url in stale {
+            self.set_redirect(&url, None);
+        }
+        let freed = self.remove_cache_entries(&expired) + self.sweep(SWEEP_GRACE_PERIOD) + self.enforce_max_size("");
+        self.save_cache();
+        freed
+    }
+
+    /* Delete everything, returns the bytes freed */
+    pub fn clear(&mut self) -> u64 {
+        let urls: Vec<String> = self.cache.cache.keys().cloned().collect();
+        let redirects: Vec<String> = self.cache.redirects.keys().cloned().collect();
+        for url in redirects {
+            self.set_redirect(&url, None);
+        }
+        let freed = self.remove_cache_entries(&urls) + self.sweep(0);
+        self.save_cache();
+        freed
+    }
+
+    fn header_string(response: &Response, name: HeaderName) -> String {
+        Self::header_string_from(response.headers(), name)
+    }
+
+    fn header_string_from(headers: &HeaderMap, name: HeaderName) -> String {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, truncated_response, tar_bz2, elf_header, TestServer};
+    use sha2::{Digest, Sha512};
+
+    #[test]
+    fn download_and_verify_without_writable_cache() {
+        let name = "firefox-130.0a1.en-US.linux-x86_64.tar.bz2";
+        let archive = tar_bz2(&[ ("firefox/firefox", &elf_header(2, 62)) ]);
+        let checksums = format!("{:x} sha512 {} {}\n", Sha512::digest(&archive), archive.len(), name);
+        let server = TestServer::start(move |path, _| {
+            match path {
+                "/latest" => response("302 Found", &[ &format!("Location: /nightly/{}", name) ], b""),
+                "/nightly/firefox-130.0a1.en-US.linux-x86_64.tar.bz2" => response("200 OK", &[], &archive),
+                "/nightly/firefox-130.0a1.en-US.linux-x86_64.checksums" => response("200 OK", &[], checksums.as_bytes()),
+                _ => response("404 Not Found", &[], b""),
+            }
+        });
+
+        /* Nothing can be created below a regular file, not even by root */
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let cachedir = file.path().join("cache");
+        let destdir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(&cachedir).unwrap();
+
+        let url = format!("{}/latest", server.url);
+        let path = downloader.download(&url, destdir.path()).unwrap();
+        assert_eq!(path, destdir.path().join(name));
+        assert_eq!(downloader.cached_location(&url), None);
+
+        let location = downloader.location(&url).unwrap();
+        assert_eq!(location, format!("{}/nightly/{}", server.url, name));
+        verify::verify_download(&mut downloader, &location, &path).unwrap();
+        assert!(!downloader.take_warnings().is_empty());
+        assert!(downloader.take_warnings().is_empty());
+
+        let staging = destdir.path().join("staging");
+        archive::extract(&path, &staging, &CancelToken::new(), |_, _| {}).unwrap();
+        assert!(staging.join("firefox/firefox").exists());
+    }
+
+    /* Serves `body` with an ETag, honoring Range requests, but cuts the first response halfway */
+    fn flaky_server(body: Vec<u8>, range_status: &'static str, headers: &'static [&'static str]) -> TestServer {
+        let first = std::sync::atomic::AtomicBool::new(true);
+        TestServer::start(move |_, request| {
+            let request = request.to_lowercase();
+            let etag: Vec<&str> = [ "ETag: \"v1\"" ].iter().chain(headers).copied().collect();
+            let range = request.lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.trim().trim_end_matches('-').parse::<usize>().ok());
+            match range {
+                Some(offset) if range_status == "206 Partial Content" => {
+                    let content_range = format!("Content-Range: bytes {}-{}/{}", offset, body.len() - 1, body.len());
+                    response(range_status, &[ etag.as_slice(), &[ content_range.as_str() ] ].concat(), &body[offset..])
+                }
+                Some(_) => response(range_status, &[], b""),
+                None if first.swap(false, Ordering::SeqCst) => {
+                    truncated_response("200 OK", &etag, &body, body.len() / 2)
+                }
+                None => response("200 OK", &etag, &body),
+            }
+        })
+    }
+
+    fn resume_interrupted_download_with(headers: &'static [&'static str]) {
+        let body: Vec<u8> = (0..300000u32).map(|i| i as u8).collect();
+        let server = flaky_server(body.clone(), "206 Partial Content", headers);
+        let cachedir = tempfile::tempdir().unwrap();
+        let destdir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let url = format!("{}/firefox.tar.bz2", server.url);
+
+        match downloader.download(&url, destdir.path()) {
+            Err(Error::Interrupted { bytes, .. }) => assert!(bytes > 0 && bytes < body.len() as i64),
+            other => panic!("expected an interrupted download, got {:?}", other.map(|_| ())),
+        }
+
+        let path = downloader.download(&url, destdir.path()).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), body);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].to_lowercase().contains("if-range: \"v1\""));
+    }
+
+    #[test]
+    fn resume_interrupted_download() {
+        resume_interrupted_download_with(&[]);
+    }
+
+    /* Partial files are kept for resuming, however briefly the download may be cached */
+    #[test]
+    fn resume_uncacheable_download() {
+        resume_interrupted_download_with(&[ "Cache-Control: no-cache" ]);
+        resume_interrupted_download_with(&[ "Cache-Control: max-age=0" ]);
+    }
+
+    #[test]
+    fn restart_when_range_not_satisfiable() {
+        let body: Vec<u8> = (0..300000u32).map(|i| (i * 7) as u8).collect();
+        let server = flaky_server(body.clone(), "416 Range Not Satisfiable", &[]);
+        let cachedir = tempfile::tempdir().unwrap();
+        let destdir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let url = format!("{}/firefox.tar.bz2", server.url);
+
+        assert!(downloader.download(&url, destdir.path()).is_err());
+        let path = downloader.download(&url, destdir.path()).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), body);
+        assert_eq!(server.requests().len(), 3);
+        assert!(downloader.entries().iter().all(|entry| entry.complete));
+    }
+
+    #[test]
+    fn migrate_version_1_index() {
+        let cachedir = tempfile::tempdir().unwrap();
+        let now = Utc::now().timestamp();
+        std::fs::write(cachedir.path().join("c_alias"), b"old firefox").unwrap();
+        std::fs::write(cachedir.path().join("c_located"), b"new firefox").unwrap();
+        let index = serde_json::json!({
+            "cache": {
+                "https://download.mozilla.org/?product=firefox-latest-ssl&os=linux64&lang=en-US": {
+                    "cache_file": "c_alias", "date": now, "max_age": 30 * 24 * 3600, "filename": "firefox-120.0.tar.bz2",
+                },
+                "https://download.mozilla.org/?product=firefox-beta-latest-ssl&os=linux64&lang=en-US": {
+                    "cache_file": "c_located", "date": now, "max_age": 30 * 24 * 3600, "filename": "firefox-122.0b3.tar.bz2",
+                    "location": "https://download-installer.cdn.mozilla.net/pub/firefox/releases/122.0b3/linux-x86_64/en-US/firefox-122.0b3.tar.bz2",
+                },
+            },
+        });
+        std::fs::write(cachedir.path().join("cache.json"), index.to_string()).unwrap();
+
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let entries = downloader.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, "firefox-122.0b3.tar.bz2");
+        assert!(entries[0].url.starts_with("https://download-installer.cdn.mozilla.net/"));
+        assert!(!cachedir.path().join("c_alias").exists());
+        assert!(cachedir.path().join("c_located").exists());
+        assert_eq!(downloader.cached_location("https://download.mozilla.org/?product=firefox-latest-ssl&os=linux64&lang=en-US"), None);
+    }
+
+    #[test]
+    fn clear_counts_only_deleted_files() {
+        let cachedir = tempfile::tempdir().unwrap();
+        let now = Utc::now().timestamp();
+        std::fs::write(cachedir.path().join("c_deleted"), [0u8; 1000]).unwrap();
+        /* remove_file() fails on a directory, even for root */
+        std::fs::create_dir(cachedir.path().join("c_stuck")).unwrap();
+        std::fs::write(cachedir.path().join("c_stuck/file"), [0u8; 5000]).unwrap();
+        let entry = |cache_file: &str| serde_json::json!({
+            "cache_file": cache_file, "date": now, "max_age": 3600, "filename": cache_file,
+            "location": format!("https://example.com/{}", cache_file),
+        });
+        let index = serde_json::json!({
+            "version": CACHE_VERSION,
+            "cache": {
+                "https://example.com/c_deleted": entry("c_deleted"),
+                "https://example.com/c_stuck": entry("c_stuck"),
+            },
+        });
+        std::fs::write(cachedir.path().join("cache.json"), index.to_string()).unwrap();
+
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        assert_eq!(downloader.clear(), 1000);
+        assert!(matches!(downloader.take_warnings().as_slice(), [Error::Io { .. }]));
+    }
+
+    #[test]
+    fn cancel_while_retrying() {
+        let server = TestServer::start(|_, _| response("503 Service Unavailable", &[], b""));
+        let cachedir = tempfile::tempdir().unwrap();
+        let destdir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::builder(cachedir.path())
+            .retries(10, std::time::Duration::from_secs(20))
+            .build()
+            .unwrap();
+
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            canceller.cancel();
+        });
+        let start = std::time::Instant::now();
+        let result = downloader.download_with_progress(&format!("{}/firefox.tar.bz2", server.url), destdir.path(), &cancel, |_,_| {});
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(server.requests().len(), 1);
     }
 }
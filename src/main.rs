@@ -3,9 +3,16 @@
 
 use chrono::Duration;
 use chrono::prelude::*;
-use firefox_user_installer::Downloader;
+use firefox_user_installer::{CancelToken, Downloader};
 use firefox_user_installer::Error;
-use regex::Regex;
+use firefox_user_installer::architecture;
+use firefox_user_installer::archive;
+use firefox_user_installer::config::{Config, NetworkConfig, SystemConfig};
+use firefox_user_installer::install_info::InstallInfo;
+use firefox_user_installer::languages::{fallback_languages, languages, negotiate_language, preferred_locales};
+use firefox_user_installer::mirror;
+use firefox_user_installer::product_details::{self, PRODUCT_DETAILS_URL};
+use firefox_user_installer::verify;
 use std::collections::HashMap;
 use std::os::unix::process::CommandExt;
 use std::path::{ Path, PathBuf };
@@ -14,6 +21,8 @@ use std::thread;
 use std::sync::{Arc, Mutex};
 use std::rc::Rc;
 use std::env;
+use std::io::Write;
+use std::time::Instant;
 
 use gtk::prelude::*;
 use gtk::{
@@ -29,14 +38,152 @@ use gtk::{
     MessageDialog,
     DialogFlags,
     MessageType,
-    ButtonsType
+    ButtonsType,
+    ResponseType,
+    FileChooserAction,
+    FileChooserDialog,
+    FileFilter
 };
 use gtk::glib;
 use gtk::glib::clone;
 
+/* Channels offered by the installer: (name, mozilla product id, display name) */
+type Channel = (&'static str, &'static str, &'static str);
+
+const CHANNELS: &[Channel] = &[
+    ("release", "firefox-latest-ssl", "Firefox"),
+    ("beta", "firefox-beta-latest-ssl", "Firefox Beta"),
+    ("devedition", "firefox-devedition-latest-ssl", "Firefox Developer Edition"),
+    ("nightly", "firefox-nightly-latest-l10n-ssl", "Firefox Nightly"),
+    ("esr", "firefox-esr-latest-ssl", "Firefox Extended Support Release"),
+];
+
+/* Architectures offered by the installer: (mozilla os id, display name) */
+const ARCHITECTURES: &[(&str, &str)] = &[
+    ("linux64", "Linux 64-bit"),
+    ("linux", "Linux 32-bit"),
+    ("linux64-aarch64", "Linux ARM64"),
+];
+
+/* Defaults and locks set by the administrator */
+const SYSTEM_CONFIG_DIR: &str = "/etc/firefox-user-installer";
+
+const USAGE: &str = "Usage: firefox --install [--channel CHANNEL] [--arch ARCH] [--lang LANG] [--mirror URL] [--version VERSION] [--force]\n       firefox --from-file ARCHIVE [--checksum HASH] [--channel CHANNEL] [--lang LANG]\n       firefox --rollback [--channel CHANNEL]\n       firefox --status [--channel CHANNEL] [--json]\n       firefox --cache-list | --cache-prune | --cache-clear\n       firefox [--channel CHANNEL] [FIREFOX OPTIONS]";
+
+/* Where firefox is downloaded from */
+#[derive(Clone, Default)]
+struct InstallSource {
+    /* Base url of a mirror laid out like archive.mozilla.org/pub/firefox */
+    mirror: Option<String>,
+    /* Exact version to install instead of the latest, e.g. "115.8.0esr" */
+    version: Option<String>,
+    /* Archive already at hand, installed instead of downloading one */
+    file: Option<PathBuf>,
+    /* Expected sha512 or sha256 of `file` */
+    checksum: Option<String>,
+    /* Proxy and timeouts, from the user and system configuration */
+    network: NetworkConfig,
+}
+
+/* Version given to unpin a channel */
+const LATEST_VERSION: &str = "latest";
+
 enum Progress {
     Status(String),
     Percent(i64,i64),
+    Transfer(Transfer),
+    /* Something went wrong without stopping the install, e.g. a read-only cache */
+    Warning(String),
+}
+
+/* State of a download, e.g. "34.2 / 81.0 MB — 2.1 MB/s — 0:22 left" */
+struct Transfer {
+    current: u64,
+    total: Option<u64>,
+    /* Bytes per second, unknown until a second has passed */
+    speed: Option<f64>,
+}
+
+impl Transfer {
+    fn fraction(&self) -> Option<f64> {
+        self.total.map(|total| self.current as f64 / total.max(1) as f64)
+    }
+
+    /* Seconds left */
+    fn eta(&self) -> Option<u64> {
+        let remaining = self.total?.saturating_sub(self.current);
+        self.speed
+            .filter(|speed| *speed > 0.0)
+            .map(|speed| (remaining as f64 / speed).ceil() as u64)
+    }
+
+    fn text(&self) -> String {
+        let mut result = match self.total {
+            Some(total) => format!("{:.1} / {}", self.current as f64 / (1024.0 * 1024.0), format_size(total)),
+            None => format_size(self.current),
+        };
+        if let Some(speed) = self.speed {
+            result += &format!(" — {}/s", format_size(speed as u64));
+        }
+        if let Some(eta) = self.eta() {
+            result += &match eta {
+                0..=3599 => format!(" — {}:{:02} left", eta / 60, eta % 60),
+                _ => format!(" — {}:{:02}:{:02} left", eta / 3600, eta / 60 % 60, eta % 60),
+            };
+        }
+        result
+    }
+}
+
+/* Minimum time between two progress events */
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/* Throughput is averaged over this period */
+const SPEED_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
+
+/*
+    Turns the byte counts reported for every chunk read into a few events
+    per second, and keeps track of the throughput
+*/
+struct ProgressMeter {
+    samples: std::collections::VecDeque<(Instant,u64)>,
+    last_event: Option<Instant>,
+}
+
+impl ProgressMeter {
+    fn new() -> ProgressMeter {
+        ProgressMeter {
+            samples: std::collections::VecDeque::new(),
+            last_event: None,
+        }
+    }
+
+    /* None when too little time passed since the previous event */
+    fn update(&mut self, current: i64, total: i64) -> Option<Transfer> {
+        let now = Instant::now();
+        let current = current.max(0) as u64;
+        let total = if total >= 0 { Some(total as u64) } else { None };
+
+        self.samples.push_back((now, current));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= SPEED_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let done = total == Some(current);
+        if !done && self.last_event.is_some_and(|last| now.duration_since(last) < PROGRESS_INTERVAL) {
+            return None;
+        }
+        self.last_event = Some(now);
+
+        let (start, start_bytes) = self.samples[0];
+        let elapsed = now.duration_since(start).as_secs_f64();
+        let speed = if elapsed >= 1.0 {
+            Some(current.saturating_sub(start_bytes) as f64 / elapsed)
+        } else {
+            None
+        };
+        Some(Transfer { current, total, speed })
+    }
 }
 
 enum InstallEvent {
@@ -45,6 +192,113 @@ enum InstallEvent {
     Error(Error),
 }
 
+/*
+    Command-line options understood by the installer. Everything else is
+    forwarded to firefox.
+*/
+#[derive(Default)]
+struct Options {
+    reset: bool,
+    install: bool,
+    force: bool,
+    rollback: bool,
+    status: bool,
+    json: bool,
+    cache_list: bool,
+    cache_prune: bool,
+    cache_clear: bool,
+    channel: Option<String>,
+    architecture: Option<String>,
+    lang: Option<String>,
+    mirror: Option<String>,
+    version: Option<String>,
+    from_file: Option<PathBuf>,
+    checksum: Option<String>,
+    firefox_args: Vec<String>,
+}
+
+/* Options selecting what the installer does instead of launching firefox */
+const INSTALLER_MODES: &[&str] = &[
+    "--reset", "--install", "--from-file", "--rollback", "--status",
+    "--cache-list", "--cache-prune", "--cache-clear",
+];
+
+fn has_installer_mode(args: &[String]) -> bool {
+    args.iter().any(|arg| INSTALLER_MODES.contains(&arg.split('=').next().unwrap_or_default()))
+}
+
+/*
+    Installer options are only ours with an installer mode, otherwise they
+    go to firefox like everything else. --channel is always ours: it picks
+    the firefox to launch.
+*/
+fn parse_args<I>(args: I) -> Result<Options,String>
+where
+    I: IntoIterator<Item = String>
+{
+    let mut options = Options::default();
+    let args: Vec<String> = args.into_iter().collect();
+    let installer = has_installer_mode(&args);
+    /* Without --install, --version is firefox's own option */
+    let install = args.iter().any(|arg| arg == "--install");
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (String::from(name), Some(String::from(value))),
+            _ => (arg.clone(), None),
+        };
+
+        let mut value = || -> Result<String,String> {
+            match inline_value.clone().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => Err(format!("Missing value for {}", name)),
+            }
+        };
+
+        match name.as_str() {
+            "--reset" => options.reset = true,
+            "--install" => options.install = true,
+            "--rollback" => options.rollback = true,
+            "--status" => options.status = true,
+            "--cache-list" => options.cache_list = true,
+            "--cache-prune" => options.cache_prune = true,
+            "--cache-clear" => options.cache_clear = true,
+            "--from-file" => options.from_file = Some(PathBuf::from(value()?)),
+            "--channel" => options.channel = Some(value()?),
+            "--force" if installer => options.force = true,
+            "--json" if installer => options.json = true,
+            "--arch" if installer => options.architecture = Some(value()?),
+            "--lang" if installer => options.lang = Some(value()?),
+            "--mirror" if installer => options.mirror = Some(value()?),
+            "--version" if install => options.version = Some(value()?),
+            "--checksum" if installer => options.checksum = Some(value()?),
+            _ => options.firefox_args.push(arg),
+        }
+    }
+    Ok(options)
+}
+
+/* Accepts either a channel name (e.g. "esr") or a mozilla product id */
+fn find_channel(channel: &str) -> Option<&'static Channel> {
+    CHANNELS.iter().find(|elem| elem.0 == channel || elem.1 == channel)
+}
+
+/* What the system runs, which is not necessarily what the installer was built for */
+fn default_architecture() -> &'static str {
+    architecture::detect()
+        .or_else(|| architecture::from_machine(env::consts::ARCH))
+        .unwrap_or("linux64")
+}
+
+fn check_architecture(os: &str) -> Result<(),Error> {
+    match architecture::detect() {
+        Some(host) if !architecture::can_run(host, os) => {
+            Err(Error::Config(format!("This system ({}) cannot run {} builds of firefox", host, os)))
+        }
+        _ => Ok(())
+    }
+}
+
 fn get_datadir() -> PathBuf {
     let dirs = directories::ProjectDirs::from("", "", "firefox-user-installer").unwrap();
     let result = PathBuf::from(dirs.data_dir());
@@ -55,78 +309,511 @@ fn get_datadir() -> PathBuf {
     result
 }
 
+/* Each channel is installed into its own directory, so they can coexist */
+fn get_appdir(datadir: &Path, channel: &str) -> PathBuf {
+    [datadir, Path::new("channels"), Path::new(channel)].iter().collect()
+}
+
+fn get_install_info_file(appdir: &Path) -> PathBuf {
+    appdir.join("install.json")
+}
+
+/* What is installed for a channel, with the version refreshed from disk */
+fn installed_info(datadir: &Path, channel: &str) -> Option<InstallInfo> {
+    let appdir = get_appdir(datadir, channel);
+    let mut info = InstallInfo::load(&get_install_info_file(&appdir)).unwrap_or_default();
+    info.refresh(&appdir.join("firefox")).ok()?;
+    Some(info).filter(|info| !info.version.is_empty())
+}
+
+/* Version of a channel to install: the pinned one, or the latest, on the mirror if there is one */
+fn latest_version(downloader: &mut Downloader, source: &InstallSource, channel: &str) -> Result<String,Error> {
+    if let Some(version) = &source.version {
+        return Ok(version.clone());
+    }
+    match &source.mirror {
+        Some(mirror) => mirror::latest_version(downloader, mirror, channel),
+        None => product_details::latest_version(downloader, PRODUCT_DETAILS_URL, channel),
+    }
+}
+
+/* Versions of a channel that can be pinned, newest first */
+fn pinnable_versions(downloader: &mut Downloader, source: &InstallSource, channel: &str) -> Result<Vec<String>,Error> {
+    let base = source.mirror.as_deref().unwrap_or(mirror::ARCHIVE_URL);
+    let mut result = mirror::versions(downloader, base, channel)?;
+    result.reverse();
+    Ok(result)
+}
+
+/* Languages offered for a channel; a mirror may only carry some of them */
+fn channel_languages(downloader: &mut Downloader, source: &InstallSource, channel: &str, architecture: &str) -> Vec<(String,String)> {
+    match &source.mirror {
+        Some(mirror) => {
+            let result = latest_version(downloader, source, channel)
+                .and_then(|version| mirror::languages(downloader, mirror, &version, architecture));
+            match result {
+                Ok(result) if !result.is_empty() => result,
+                Ok(_) => fallback_languages(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    fallback_languages()
+                }
+            }
+        }
+        None => languages(downloader, PRODUCT_DETAILS_URL, channel),
+    }
+}
+
+/* e.g. "121.0 (installed: 120.0.1)", blocks on the network */
+fn version_text(datadir: &Path, channel: &str, source: &InstallSource) -> String {
+    let latest = new_downloader(datadir, &source.network)
+        .and_then(|mut downloader| {
+            let result = latest_version(&mut downloader, source, channel);
+            report_warnings(&mut downloader);
+            result
+        })
+        .unwrap_or_else(|_| String::from("Unknown"));
+    match installed_info(datadir, channel) {
+        Some(info) if info.has_version(&latest) => format!("{} (installed)", latest),
+        Some(info) => format!("{} (installed: {})", latest, info.full_version()),
+        None => latest,
+    }
+}
+
+fn is_installed(datadir: &Path, channel: &str) -> bool {
+    get_appdir(datadir, channel).join("firefox/firefox").exists()
+}
+
+/* Channel launched when none is given: release if installed, else the first installed one */
+fn default_channel(datadir: &Path) -> &'static Channel {
+    CHANNELS.iter()
+        .find(|channel| is_installed(datadir, channel.0))
+        .unwrap_or(&CHANNELS[0])
+}
+
+/* The channel locked by the administrator, if any */
+fn locked_channel(system: &SystemConfig) -> Option<&'static Channel> {
+    system.channel.as_deref()
+        .filter(|_| system.is_locked("channel"))
+        .and_then(find_channel)
+}
+
+/*
+    Channel launched by plain `firefox`. The last channel installed is only
+    a choice pre-filled in the dialog: it must not take over the "Firefox"
+    menu entry, which would leave release without one.
+*/
+fn launch_channel(datadir: &Path, system: &SystemConfig) -> &'static Channel {
+    locked_channel(system).unwrap_or_else(|| default_channel(datadir))
+}
+
+/*
+    Older versions installed a single firefox into <datadir>/app. Adopt it as
+    the release channel.
+*/
+fn migrate_legacy_install(datadir: &Path) {
+    let legacy = datadir.join("app");
+    let appdir = get_appdir(datadir, "release");
+    if legacy.exists() && !appdir.exists() {
+        if let Some(parent) = appdir.parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
+        std::fs::rename(&legacy, &appdir).ok();
+        let previous = sibling_dir(&legacy, "previous");
+        if previous.exists() {
+            std::fs::rename(&previous, sibling_dir(&appdir, "previous")).ok();
+        }
+    }
+}
+
+fn get_config_file(datadir: &Path) -> PathBuf {
+    datadir.join("config.json")
+}
+
+/* A broken configuration must not prevent firefox from starting */
+fn load_config(datadir: &Path) -> Config {
+    Config::load(&get_config_file(datadir)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Config::default()
+    })
+}
+
+fn load_system_config() -> SystemConfig {
+    let architectures: Vec<&str> = ARCHITECTURES.iter().map(|elem| elem.0).collect();
+    SystemConfig::load_dir(Path::new(SYSTEM_CONFIG_DIR)).map(|mut system| {
+        system.validate(|channel| find_channel(channel).map(|channel| channel.0), &architectures);
+        for problem in system.problems() {
+            eprintln!("Warning: {}", problem);
+        }
+        system
+    }).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        SystemConfig::default()
+    })
+}
+
+fn new_downloader(datadir: &Path, network: &NetworkConfig) -> Result<Downloader,Error> {
+    Downloader::builder(&get_cachedir(datadir))
+        .network(network)
+        .build()
+}
+
+/* For downloads made outside of an install, which have no progress to report to */
+fn report_warnings(downloader: &mut Downloader) {
+    for warning in downloader.take_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+/* Downloads still work without a cache, so a missing cache dir is not fatal */
 fn get_cachedir(datadir: &Path) -> PathBuf {
     let result: PathBuf = [datadir, Path::new("cache")].iter().collect();
     if !result.exists() {
-        std::fs::create_dir_all(&result)
-            .unwrap_or_else(|e| panic!("Failed to create directory {}: {}", result.display(), e));
+        if let Err(e) = std::fs::create_dir_all(&result) {
+            eprintln!("Warning: cannot create directory {}: {}", result.display(), e);
+        }
     }
     result
 }
 
-fn install<F>(datadir: &Path, appdir: &Path, browser: &str, architecture: &str, lang: &str, observer: F)  -> Result<(),Error>
+/*
+    Download the archive to install into workdir, and check it against
+    Mozilla's signed checksums. Returns it with its version, when known.
+*/
+#[allow(clippy::too_many_arguments)]
+fn download_archive<F>(datadir: &Path, workdir: &Path, browser: &str, architecture: &str, lang: &str, source: &InstallSource, cancel: &CancelToken, observer: &F) -> Result<(PathBuf,Option<String>),Error>
 where
     F: Fn(Progress)
 {
-    let cachedir = get_cachedir(datadir);
-    let mut downloader = Downloader::new(&cachedir);
+    let mut downloader = Downloader::builder(&get_cachedir(datadir))
+        .network(&source.network)
+        .cancel(cancel)
+        .build()?;
+    downloader.cleanup();
+    let channel = find_channel(browser).unwrap_or(&CHANNELS[0]);
+
+    observer(Progress::Status(String::from("Checking latest version...")));
+    let (url, version) = match (&source.version, &source.mirror) {
+        /* Pinned versions come straight from the archive, bouncer only knows the latest */
+        (Some(version), mirror) => {
+            let base = mirror.as_deref().unwrap_or(mirror::ARCHIVE_URL);
+            if !mirror::versions(&mut downloader, base, channel.0)?.contains(version) {
+                return Err(Error::Config(format!("{} {} is not available from {}", channel.2, version, base)));
+            }
+            let url = mirror::archive_url(&mut downloader, base, version, architecture, lang)?;
+            (url, Some(version.clone()))
+        }
+        (None, Some(mirror)) => {
+            let version = mirror::latest_version(&mut downloader, mirror, channel.0)?;
+            let url = mirror::archive_url(&mut downloader, mirror, &version, architecture, lang)?;
+            (url, Some(version))
+        }
+        (None, None) => {
+            let url = format!("https://download.mozilla.org/?product={}&os={}&lang={}", browser, architecture, lang);
+
+            /* Don't reuse a cached download of an older version */
+            let version = product_details::latest_version(&mut downloader, PRODUCT_DETAILS_URL, channel.0).ok();
+            if let (Some(version), Some(location)) = (&version, downloader.cached_location(&url)) {
+                if !location.contains(&format!("/{}/", version)) && !location.contains(&format!("-{}.", version)) {
+                    downloader.evict(&url);
+                }
+            }
+            (url, version)
+        }
+    };
+
+    cancel.check()?;
 
-    let url = format!("https://download.mozilla.org/?product={}&os={}&lang={}", browser, architecture, lang);
-    // let url = String::from("http://localhost:8000/firefox-95.0.tar.bz2");
+    observer(Progress::Status(match &version {
+        Some(version) => format!("Downloading {}...", version),
+        None => String::from("Downloading..."),
+    }));
+    let meter = std::cell::RefCell::new(ProgressMeter::new());
+    let outpath = downloader.download_with_progress(&url, workdir, cancel, |current,total| {
+        if let Some(transfer) = meter.borrow_mut().update(current, total) {
+            observer(Progress::Transfer(transfer));
+        }
+    })?;
+    for warning in downloader.take_warnings() {
+        observer(Progress::Warning(warning.to_string()));
+    }
+
+    /* Never extract something we could not authenticate, and don't keep it around */
+    observer(Progress::Status(String::from("Verifying...")));
+    let location = downloader.location(&url)
+        .ok_or_else(|| Error::Verification(String::from("Cannot determine where the archive was downloaded from")))?;
+    if let Err(e) = verify::verify_download(&mut downloader, &location, &outpath) {
+        downloader.evict(&url);
+        return Err(e);
+    }
+    for warning in downloader.take_warnings() {
+        observer(Progress::Warning(warning.to_string()));
+    }
+    Ok((outpath, version))
+}
 
+/*
+    Cancelling stops the install before the new files are swapped in, and
+    removes everything it downloaded or extracted
+*/
+#[allow(clippy::too_many_arguments)]
+fn install<F>(datadir: &Path, appdir: &Path, browser: &str, architecture: &str, lang: &str, source: &InstallSource, cancel: &CancelToken, observer: F)  -> Result<(),Error>
+where
+    F: Fn(Progress)
+{
     let workdir = tempfile::Builder::new()
         .prefix("ffui")
-        .tempdir()
-        .unwrap();
- 
-    observer(Progress::Status(String::from("Downloading...")));
-    let outpath = downloader.download_with_progress(&url, workdir.path(), |current,total| {
-        observer(Progress::Percent(current, total));
-    })?;
+        .tempdir()?;
+    let (outpath, version) = match &source.file {
+        Some(file) => {
+            observer(Progress::Status(String::from("Verifying...")));
+            archive::check_format(file)?;
+            if let Some(checksum) = &source.checksum {
+                verify::verify_checksum(file, checksum)?;
+            }
+            (file.clone(), None)
+        }
+        None => {
+            check_architecture(architecture)?;
+            download_archive(datadir, workdir.path(), browser, architecture, lang, source, cancel, &observer)?
+        }
+    };
+    cancel.check()?;
 
-    /* Extract into appdir */
+    /* Extract next to appdir, so that a failure leaves the current install untouched */
     observer(Progress::Status(String::from("Extracting...")));
-    std::fs::create_dir_all(appdir)?;
-    let status = process::Command::new("tar")
-        .arg("xf")
-        .arg(outpath)
-        .arg("-C")
-        .arg(appdir.as_os_str())
-        .status()?;
-    if !status.success() {
-        return Err(Error::new("Extraction failed"));
+    let staging = sibling_dir(appdir, "staging");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
     }
+    let meter = std::cell::RefCell::new(ProgressMeter::new());
+    let result = archive::extract(&outpath, &staging, cancel, |current,total| {
+        if meter.borrow_mut().update(current, total).is_some() {
+            observer(Progress::Percent(current, total));
+        }
+    }).and_then(|_| cancel.check());
     workdir.close();
+    if let Err(e) = result {
+        std::fs::remove_dir_all(&staging).ok();
+        return Err(e);
+    }
 
-    let executable = Path::new(appdir).join("firefox/firefox");
+    let executable = Path::new(&staging).join("firefox/firefox");
     if !executable.exists() {
-        return Err(Error::new("Executable not found after extraction"));
+        std::fs::remove_dir_all(&staging).ok();
+        return Err(Error::Extraction(String::from("Executable not found after extraction")));
+    }
+
+    /*
+        A mirror may well have put a build of another architecture there.
+        A local archive is whatever it is, as long as we can run it.
+    */
+    let built_for = architecture::from_elf(&executable)
+        .map_err(|e| Error::Extraction(e.to_string()))
+        .and_then(|os| if os == architecture || source.file.is_some() {
+            check_architecture(os).map(|_| os)
+        } else {
+            Err(Error::Extraction(format!("The archive contains a {} build instead of {}", os, architecture)))
+        });
+    let architecture = match built_for {
+        Ok(os) => os,
+        Err(e) => {
+            std::fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+    };
+
+    /* Kept inside the install, so that it follows it on rollback */
+    match InstallInfo::read(&staging.join("firefox")) {
+        Ok(mut info) => {
+            info.mark_installed(architecture, lang, version.as_deref());
+            if let Err(e) = info.save(&get_install_info_file(&staging)) {
+                eprintln!("Cannot save installation details: {}", e);
+            }
+        }
+        Err(e) => {
+            eprintln!("Cannot read installed version: {}", e);
+        }
+    }
+
+    if source.version.is_some() {
+        if let Err(e) = write_update_policy(&staging.join("firefox")) {
+            std::fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+    }
+
+    replace_install(appdir, &staging)?;
+
+    if let Some(channel) = find_channel(browser) {
+        let mut config = load_config(datadir);
+        config.record_install(channel.0, architecture, lang, source.version.as_deref());
+        if let Err(e) = config.save(&get_config_file(datadir)) {
+            eprintln!("Cannot save configuration: {}", e);
+        }
+    }
+
+    /* The shipped firefox.desktop launches the default channel, add entries for the others */
+    if let Some(channel) = find_channel(browser).filter(|channel| channel.0 != "release") {
+        if let Err(e) = write_desktop_entry(channel) {
+            eprintln!("Cannot create menu entry for {}: {}", channel.2, e);
+        }
     }
 
     Ok(())
 }
 
-fn languages(downloader: &mut Downloader) -> Vec<(String,String)> {
-    let mut result = Vec::new();
+/*
+    Firefox would update itself away from a pinned version, unless an
+    enterprise policy in its distribution directory says otherwise
+*/
+fn write_update_policy(firefox_dir: &Path) -> Result<(),Error> {
+    let dir = firefox_dir.join("distribution");
+    std::fs::create_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+
+    /* Keep the other policies of a distribution that ships some */
+    let path = dir.join("policies.json");
+    let mut policies = std::fs::read_to_string(&path).ok()
+        .and_then(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok())
+        .filter(|policies| policies["policies"].is_object())
+        .unwrap_or_else(|| serde_json::json!({ "policies": {} }));
+    policies["policies"]["DisableAppUpdate"] = serde_json::Value::Bool(true);
+    std::fs::write(&path, serde_json::to_string_pretty(&policies)?).map_err(|e| Error::io(&path, e))?;
+    Ok(())
+}
+
+fn write_desktop_entry(channel: &Channel) -> Result<(),Error> {
+    let dirs = directories::BaseDirs::new()
+        .ok_or_else(|| Error::new("Cannot determine home directory"))?;
+    let dir = dirs.data_dir().join("applications");
+    std::fs::create_dir_all(&dir)?;
+
+    let icon = get_appdir(&get_datadir(), channel.0).join("firefox/browser/chrome/icons/default/default128.png");
+    let contents = format!("\
+[Desktop Entry]
+Name={name}
+Comment=Web Browser
+Exec=/usr/bin/firefox --channel {channel} %u
+Terminal=false
+Type=Application
+Icon={icon}
+Categories=Network;WebBrowser;
+MimeType=text/html;text/xml;application/xhtml+xml;application/xml;application/vnd.mozilla.xul+xml;application/rss+xml;application/rdf+xml;image/gif;image/jpeg;image/png;x-scheme-handler/http;x-scheme-handler/https;
+StartupNotify=true
+Actions=Private;
+
+[Desktop Action Private]
+Exec=/usr/bin/firefox --channel {channel} --private-window %u
+Name=Open in private mode
+",
+        name = channel.2,
+        channel = channel.0,
+        icon = icon.display());
 
-    let contents = downloader.download_to_string("https://www.mozilla.org/en-US/firefox/all/#product-desktop-release").unwrap();
+    std::fs::write(dir.join(format!("firefox-user-installer-{}.desktop", channel.0)), contents)?;
+    Ok(())
+}
 
-    let rex0 = Regex::new(r#"(?s)<select id="select_desktop_release_language".*?>.*?</select>"#).unwrap();
-    let mat0 = rex0.find(&contents).unwrap();
+/* <parent>/app -> <parent>/app.<suffix> */
+fn sibling_dir(appdir: &Path, suffix: &str) -> PathBuf {
+    let mut name = appdir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    appdir.with_file_name(name)
+}
 
-    let rex1 = Regex::new(r#"<option value="(.*?)">(.*?)</option>"#).unwrap();
-    result.push((String::from("en-US"), String::from("English (US)")));
-    for mat in rex1.captures_iter(mat0.as_str()) {
-        let code = mat.get(1).unwrap().as_str();
-        let name = mat.get(2).unwrap().as_str();
+/*
+    Swap a freshly extracted tree in place of appdir. The current install is
+    kept as app.previous so that it can be restored with --rollback. The
+    former app.previous is set aside as app.rollback, and only deleted once
+    the swap succeeded.
+*/
+fn replace_install(appdir: &Path, staging: &Path) -> Result<(),Error> {
+    let previous = sibling_dir(appdir, "previous");
+    let rollback = sibling_dir(appdir, "rollback");
+    if rollback.exists() {
+        std::fs::remove_dir_all(&rollback)?;
+    }
 
-        result.push((String::from(code), String::from(name)));
+    let installed = appdir.exists();
+    if installed {
+        if previous.exists() {
+            std::fs::rename(&previous, &rollback)?;
+        }
+        if let Err(e) = std::fs::rename(appdir, &previous) {
+            if rollback.exists() {
+                std::fs::rename(&rollback, &previous).ok();
+            }
+            return Err(Error::from(e));
+        }
     }
 
-    result
+    if let Err(e) = std::fs::rename(staging, appdir) {
+        if installed {
+            std::fs::rename(&previous, appdir).ok();
+        }
+        if rollback.exists() {
+            std::fs::rename(&rollback, &previous).ok();
+        }
+        return Err(Error::from(e));
+    }
+    if rollback.exists() {
+        std::fs::remove_dir_all(&rollback)?;
+    }
+    Ok(())
+}
+
+/*
+    Exchange appdir and app.previous, so that a second rollback undoes the first
+*/
+fn rollback(appdir: &Path) -> Result<(),Error> {
+    let previous = sibling_dir(appdir, "previous");
+    if !previous.join("firefox/firefox").exists() {
+        return Err(Error::new("No previous installation to roll back to"));
+    }
+
+    let rollback = sibling_dir(appdir, "rollback");
+    if rollback.exists() {
+        std::fs::remove_dir_all(&rollback)?;
+    }
+    if appdir.exists() {
+        std::fs::rename(appdir, &rollback)?;
+    }
+    if let Err(e) = std::fs::rename(&previous, appdir) {
+        if rollback.exists() {
+            std::fs::rename(&rollback, appdir).ok();
+        }
+        return Err(Error::from(e));
+    }
+    if rollback.exists() {
+        std::fs::rename(&rollback, &previous)?;
+    }
+    Ok(())
+}
+
+/*
+    If we were interrupted in the middle of replace_install() or rollback(),
+    appdir may be missing while app.previous still holds a working tree, and
+    app.rollback may be left over
+*/
+fn recover_install(appdir: &Path) {
+    let previous = sibling_dir(appdir, "previous");
+    if !appdir.exists() && previous.join("firefox/firefox").exists() {
+        std::fs::rename(&previous, appdir).ok();
+    }
+
+    /* app.rollback is the install that was set aside: the previous one, unless there still is one */
+    let rollback = sibling_dir(appdir, "rollback");
+    if rollback.exists() {
+        if previous.exists() {
+            std::fs::remove_dir_all(&rollback).ok();
+        } else {
+            std::fs::rename(&rollback, &previous).ok();
+        }
+    }
 }
 
-fn find_language<'a>(languages: &'a Vec<(String,String)>, lang: &str) -> Option<&'a str> {
+fn find_language<'a>(languages: &'a [(String,String)], lang: &str) -> Option<&'a str> {
     let found = languages.iter().find(|elem| {
         elem.0 == lang
     });
@@ -137,27 +824,11 @@ fn find_language<'a>(languages: &'a Vec<(String,String)>, lang: &str) -> Option<
     None
 }
 
-fn detect_language(languages: &Vec<(String,String)>) -> Option<&str> {
-    if let Ok(lang) = env::var("LANG") {
-        let lang = lang.split(".").nth(0);
-        if let Some(lang) = lang {
-            let lang = lang.replace("_", "-");
-
-            /* Find with the form: en-US */
-            let found = find_language(languages, &lang);
-            if let Some(lang) = found {
-                return Some(lang);
-            }
-
-            /* If not found, find with the form: en */
-            let lang = lang.split("-").next().unwrap();
-            return find_language(languages, &lang);
-        }
-    }
-    None
+fn detect_language(languages: &[(String,String)]) -> Option<&str> {
+    negotiate_language(&preferred_locales(), languages)
 }
 
-fn on_ok(app: Rc<Application>, browser: &str, architecture: &str, lang: &str) {
+fn on_ok(app: Rc<Application>, browser: &str, architecture: &str, lang: &str, source: &InstallSource) {
     let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
 
     let window = Window::builder()
@@ -181,9 +852,51 @@ fn on_ok(app: Rc<Application>, browser: &str, architecture: &str, lang: &str) {
         .expand(true)
         .build();
     grid.attach(&progressbar, 0, 0, 1, 1);
+
+    /* Warnings stay visible below the progress bar, which keeps changing */
+    let warning_label = Label::builder()
+        .wrap(true)
+        .xalign(0.0)
+        .no_show_all(true)
+        .build();
+    grid.attach(&warning_label, 0, 1, 2, 1);
+
+    let channel = find_channel(browser).unwrap_or(&CHANNELS[0]).0;
+    let cancel = CancelToken::new();
+    let finished = Rc::new(std::cell::Cell::new(false));
+    let cancel_button = Button::builder()
+        .label("Cancel")
+        .build();
+    grid.attach(&cancel_button, 1, 0, 1, 1);
+    cancel_button.connect_clicked(clone!(@strong cancel, @strong finished, @weak progressbar, @weak window => move |button| {
+        /* After a successful install with warnings this starts the browser */
+        if finished.get() {
+            window.close();
+            run_app(channel);
+            return;
+        }
+        cancel.cancel();
+        button.set_sensitive(false);
+        progressbar.set_text(Some("Cancelling..."));
+    }));
+
+    /* Closing the window cancels, it goes away once the worker reported back */
+    window.connect_delete_event(clone!(@weak cancel_button, @strong finished => @default-return Inhibit(false), move |_, _| {
+        if finished.get() {
+            return Inhibit(false);
+        }
+        if cancel_button.is_sensitive() {
+            cancel_button.clicked();
+        }
+        Inhibit(true)
+    }));
     window.show_all();
 
-    rx.attach(None, move |event| {
+    let browser = String::from(browser);
+    let architecture = String::from(architecture);
+    let lang = String::from(lang);
+    let source = source.clone();
+    rx.attach(None, clone!(@strong browser, @strong architecture, @strong lang, @strong source, @strong cancel, @strong finished => move |event| {
         match event {
             InstallEvent::Progress(progress) => match progress {
                 Progress::Status(text) => {
@@ -197,36 +910,76 @@ fn on_ok(app: Rc<Application>, browser: &str, architecture: &str, lang: &str) {
                         progressbar.pulse();
                     }
                 }
+                Progress::Warning(text) => {
+                    eprintln!("Warning: {}", text);
+                    let shown = warning_label.text();
+                    if shown.is_empty() {
+                        warning_label.set_text(&format!("Warning: {}", text));
+                    } else {
+                        warning_label.set_text(&format!("{}\nWarning: {}", shown, text));
+                    }
+                    warning_label.show();
+                }
+                Progress::Transfer(transfer) => {
+                    match transfer.fraction() {
+                        Some(fraction) => progressbar.set_fraction(fraction),
+                        None => progressbar.pulse(),
+                    }
+                    /* Keep showing that we are cancelling */
+                    if !cancel.is_cancelled() {
+                        progressbar.set_text(Some(&transfer.text()));
+                    }
+                }
             }
 
             InstallEvent::Success => {
+                finished.set(true);
+                /* Let the user read the warnings before the browser replaces us */
+                if warning_label.is_visible() {
+                    progressbar.set_fraction(1.0);
+                    progressbar.set_text(Some("Installed"));
+                    cancel_button.set_label("Start");
+                    cancel_button.set_sensitive(true);
+                    return Continue(false);
+                }
+                window.close();
+                run_app(channel);
+                return Continue(false);
+            }
+            InstallEvent::Error(Error::Cancelled) => {
+                finished.set(true);
                 window.close();
-                run_app();
                 return Continue(false);
             }
             InstallEvent::Error(err) => {
+                finished.set(true);
                 let dlg = MessageDialog::new(
                     Some(&window),
                     DialogFlags::MODAL,
                     MessageType::Error,
-                    ButtonsType::Ok,
+                    ButtonsType::None,
                     &err.to_string());
-                dlg.run();
+                /* Only offer to retry when it has a chance of working */
+                if err.is_transient() {
+                    dlg.add_button("Retry", ResponseType::Accept);
+                }
+                dlg.add_button("Close", ResponseType::Close);
+                let response = dlg.run();
                 dlg.close();
+                if response == ResponseType::Accept {
+                    on_ok(Rc::clone(&app), &browser, &architecture, &lang, &source);
+                }
                 window.close();
                 return Continue(false);
             }
         }
         Continue(true)
-    });
+    }));
 
-    let browser = String::from(browser);
-    let architecture = String::from(architecture);
-    let lang = String::from(lang);
     thread::spawn(move || {
         let datadir = get_datadir();
-        let appdir = Path::new(&datadir).join("app");
-        let result = install(&datadir, &appdir, &browser, &architecture, &lang, |progress| {
+        let appdir = get_appdir(&datadir, channel);
+        let result = install(&datadir, &appdir, &browser, &architecture, &lang, &source, &cancel, |progress| {
             tx.send(InstallEvent::Progress(progress));
         });
 
@@ -243,32 +996,36 @@ fn on_ok(app: Rc<Application>, browser: &str, architecture: &str, lang: &str) {
     
 }
 
-fn run_app() {
+/* Arguments meant for firefox: all of them when they don't parse as ours */
+fn firefox_args(args: Vec<String>) -> Vec<String> {
+    match parse_args(args.clone()) {
+        Ok(options) => options.firefox_args,
+        Err(_) => args,
+    }
+}
+
+fn run_app(channel: &str) {
     let datadir = get_datadir();
-    let appdir = Path::new(&datadir).join("app");
+    let appdir = get_appdir(&datadir, channel);
     let exe = Path::new(&appdir).join("firefox/firefox");
 
     let mut proc = process::Command::new(exe);
-    for arg in env::args().skip(1) {
-        if arg != "--reset" {
-            proc.arg(arg);
-        }
-    }
+    proc.args(firefox_args(env::args().skip(1).collect()));
     
-    let error = proc.exec();
+    let error = Error::Launch(proc.exec());
     let dlg = MessageDialog::new::<MessageDialog>(
         None,
         DialogFlags::MODAL,
         MessageType::Error,
         ButtonsType::Ok,
-        &format!("Cannot launch firefox: {}", &error.to_string()));
+        &error.to_string());
     dlg.set_window_position(gtk::WindowPosition::Center);
     dlg.run();
     dlg.close();
 }
 
-fn build_ui(app: Rc<Application>) {
-    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+fn build_ui(app: Rc<Application>, channel: &Channel, config: &Config, system: &SystemConfig, source: &InstallSource) {
+    let (tx, rx) = glib::MainContext::channel::<(String,Vec<(String,String)>)>(glib::PRIORITY_DEFAULT);
 
     let window = Rc::new(ApplicationWindow::builder()
         .application(app.as_ref())
@@ -292,94 +1049,727 @@ fn build_ui(app: Rc<Application>) {
 
     let mut browser_combo = ComboBoxText::builder()
         .build();
-    browser_combo.append(Some("firefox-latest-ssl"), "Firefox");
-    browser_combo.append(Some("firefox-beta-latest-ssl"), "Firefox Beta");
-    browser_combo.append(Some("firefox-devedition-latest-ssl"), "Firefox Developer Edition");
-    browser_combo.append(Some("firefox-nightly-latest-l10n-ssl"), "Firefox Nightly");
-    browser_combo.append(Some("firefox-esr-latest-ssl"), "Firefox Extended Support Release");
-    browser_combo.set_active_id(Some("firefox-latest-ssl"));
+    for channel in CHANNELS.iter() {
+        browser_combo.append(Some(channel.1), channel.2);
+    }
+    browser_combo.set_active_id(Some(channel.1));
+    browser_combo.set_sensitive(!system.is_locked("channel"));
     grid.attach(&browser_combo, 1, 0, 1, 1);
 
     let label = Label::builder()
-        .label("Architecture:")
+        .label("Version:")
         .build();
     grid.attach(&label, 0, 1, 1, 1);
 
-    let mut architecture_combo = ComboBoxText::builder().build();
-    architecture_combo.append(Some("linux64"), "Linux 64-bit");
-    architecture_combo.append(Some("linux"), "Linux 32-bit");
-    match env::consts::ARCH {
-        "x86_64" => {
-            architecture_combo.set_active_id(Some("linux64"));
+    let version_label = Label::builder()
+        .label("...")
+        .build();
+    grid.attach(&version_label, 1, 1, 1, 1);
+
+    /* Looked up in the background, ignoring answers for a channel that is no longer selected */
+    let (version_tx, version_rx) = glib::MainContext::channel::<(String,String)>(glib::PRIORITY_DEFAULT);
+    version_rx.attach(None, clone!(@strong browser_combo, @strong version_label => move |(browser, text)| {
+        if browser_combo.active_id().as_deref() == Some(browser.as_str()) {
+            version_label.set_text(&text);
+        }
+        Continue(true)
+    }));
+    let version_source = source.clone();
+    let refresh_version = move |browser: String| {
+        let version_tx = version_tx.clone();
+        let source = version_source.clone();
+        thread::spawn(move || {
+            if let Some(channel) = find_channel(&browser) {
+                let text = version_text(&get_datadir(), channel.0, &source);
+                version_tx.send((browser, text));
+            }
+        });
+    };
+
+    let label = Label::builder()
+        .label("Pin version:")
+        .build();
+    grid.attach(&label, 0, 2, 1, 1);
+
+    let pin_combo = ComboBoxText::builder()
+        .sensitive(false)
+        .build();
+    pin_combo.append(Some(LATEST_VERSION), "Latest");
+    pin_combo.set_active_id(Some(LATEST_VERSION));
+    grid.attach(&pin_combo, 1, 2, 1, 1);
+
+    /* Releases listed in the archive (or on the mirror), also in the background */
+    let (pin_tx, pin_rx) = glib::MainContext::channel::<(String,Vec<String>)>(glib::PRIORITY_DEFAULT);
+    let pins: HashMap<String,String> = config.installs.iter()
+        .filter_map(|(channel, install)| install.version.clone().map(|version| (channel.clone(), version)))
+        .collect();
+    pin_rx.attach(None, clone!(@strong browser_combo, @strong pin_combo => move |(browser, versions)| {
+        if browser_combo.active_id().as_deref() != Some(browser.as_str()) {
+            return Continue(true);
         }
-        _ => {
-            architecture_combo.set_active_id(Some("linux"));
+
+        let current = pin_combo.active_id()
+            .map(|id| id.to_string())
+            .filter(|id| versions.contains(id))
+            .or_else(|| find_channel(&browser).and_then(|channel| pins.get(channel.0)).cloned())
+            .unwrap_or_else(|| String::from(LATEST_VERSION));
+        pin_combo.remove_all();
+        pin_combo.append(Some(LATEST_VERSION), "Latest");
+        for version in versions.iter() {
+            pin_combo.append(Some(version), version);
+        }
+        if !pin_combo.set_active_id(Some(&current)) {
+            pin_combo.set_active_id(Some(LATEST_VERSION));
         }
+        pin_combo.set_sensitive(!versions.is_empty());
+        Continue(true)
+    }));
+    let pin_source = source.clone();
+    let refresh_pins = move |browser: String| {
+        let pin_tx = pin_tx.clone();
+        let source = pin_source.clone();
+        thread::spawn(move || {
+            if let Some(channel) = find_channel(&browser) {
+                let versions = new_downloader(&get_datadir(), &source.network)
+                    .and_then(|mut downloader| {
+                        let result = pinnable_versions(&mut downloader, &source, channel.0);
+                        report_warnings(&mut downloader);
+                        result
+                    })
+                    .unwrap_or_else(|e| {
+                        eprintln!("Cannot list {} versions: {}", channel.2, e);
+                        Vec::new()
+                    });
+                pin_tx.send((browser, versions));
+            }
+        });
+    };
+
+    let label = Label::builder()
+        .label("Architecture:")
+        .build();
+    grid.attach(&label, 0, 3, 1, 1);
+
+    let mut architecture_combo = ComboBoxText::builder().build();
+    for architecture in ARCHITECTURES.iter() {
+        architecture_combo.append(Some(architecture.0), architecture.1);
     }
-    grid.attach(&architecture_combo, 1, 1, 1, 1);
+    let installed = config.installs.get(channel.0);
+    let architecture = installed.map(|install| install.architecture.as_str())
+        .or(config.architecture.as_deref())
+        .filter(|architecture| ARCHITECTURES.iter().any(|elem| elem.0 == *architecture))
+        .unwrap_or_else(|| default_architecture());
+    architecture_combo.set_active_id(Some(architecture));
+    architecture_combo.set_sensitive(!system.is_locked("architecture"));
+    grid.attach(&architecture_combo, 1, 3, 1, 1);
 
     let label = Label::builder()
         .label("Language:")
         .build();
-    grid.attach(&label, 0, 2, 1, 1);
+    grid.attach(&label, 0, 4, 1, 1);
     
     let language_combo = Rc::new(ComboBoxText::new());
-    grid.attach(language_combo.as_ref(), 1, 2, 1, 1);
-    thread::spawn(move || {
-        let datadir = get_datadir();
-        let cachedir = get_cachedir(&datadir);
-        let mut downloader = Downloader::new(&cachedir);
-        let languages = languages(&mut downloader);
-        tx.send(languages);
-    });
+    language_combo.set_sensitive(!system.is_locked("language"));
+    grid.attach(language_combo.as_ref(), 1, 4, 1, 1);
+
+    /* Each channel ships its own set of locales */
+    let languages_source = source.clone();
+    let languages_architecture = String::from(architecture);
+    let refresh_languages = move |browser: String| {
+        let tx = tx.clone();
+        let source = languages_source.clone();
+        let architecture = languages_architecture.clone();
+        thread::spawn(move || {
+            if let Some(channel) = find_channel(&browser) {
+                let datadir = get_datadir();
+                match new_downloader(&datadir, &source.network) {
+                    Ok(mut downloader) => {
+                        let languages = channel_languages(&mut downloader, &source, channel.0, &architecture);
+                        report_warnings(&mut downloader);
+                        tx.send((browser, languages));
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        });
+    };
 
     let ok_button = Rc::new(Button::builder()
         .label("OK")
         .sensitive(false)
         .build());
-    ok_button.connect_clicked(clone!(@strong app, @strong window, @strong language_combo, @strong ok_button => move |_| {
+    let source = source.clone();
+    ok_button.connect_clicked(clone!(@strong app, @strong window, @strong browser_combo, @strong pin_combo, @strong language_combo, @strong ok_button => move |_| {
         let browser = browser_combo.active_id().unwrap();
         let architecture = architecture_combo.active_id().unwrap();
         let language = language_combo.active_id().unwrap();
+        let source = InstallSource {
+            version: pin_combo.active_id()
+                .map(|id| id.to_string())
+                .filter(|id| id != LATEST_VERSION),
+            ..source.clone()
+        };
 
-        on_ok(Rc::clone(&app), &browser, &architecture, &language);
+        on_ok(Rc::clone(&app), &browser, &architecture, &language, &source);
 
         window.close();
     }));
-    grid.attach(ok_button.as_ref(), 0, 3, 2, 1);
+    grid.attach(ok_button.as_ref(), 1, 5, 1, 1);
+
+    let file_button = Button::builder()
+        .label("Choose file…")
+        .build();
+    let installs = config.installs.clone();
+    file_button.connect_clicked(clone!(@strong app, @strong window, @strong browser_combo => move |_| {
+        let dialog = FileChooserDialog::with_buttons(
+            Some("Choose a Firefox archive"),
+            Some(window.as_ref()),
+            FileChooserAction::Open,
+            &[("Cancel", ResponseType::Cancel), ("Open", ResponseType::Accept)]);
+        let filter = FileFilter::new();
+        filter.set_name(Some("Firefox archives"));
+        for pattern in ["*.tar.xz", "*.tar.bz2", "*.tar.gz"] {
+            filter.add_pattern(pattern);
+        }
+        dialog.add_filter(&filter);
+        let response = dialog.run();
+        let file = dialog.filename();
+        dialog.close();
+
+        if let (ResponseType::Accept, Some(file)) = (response, file) {
+            let browser = browser_combo.active_id().unwrap();
+            let source = InstallSource {
+                file: Some(file),
+                version: installs.get(browser.as_str()).and_then(|install| install.version.clone()),
+                ..InstallSource::default()
+            };
+
+            /* The architecture is read from the archive, its language is unknown */
+            on_ok(Rc::clone(&app), &browser, default_architecture(), "", &source);
+
+            window.close();
+        }
+    }));
+    grid.attach(&file_button, 0, 5, 1, 1);
+
+    refresh_version(String::from(channel.1));
+    refresh_pins(String::from(channel.1));
+    refresh_languages(String::from(channel.1));
+    browser_combo.connect_changed(clone!(@strong ok_button, @strong pin_combo => move |combo| {
+        if let Some(browser) = combo.active_id() {
+            version_label.set_text("...");
+            ok_button.set_sensitive(false);
+            pin_combo.set_active_id(Some(LATEST_VERSION));
+            pin_combo.set_sensitive(false);
+            refresh_version(browser.to_string());
+            refresh_pins(browser.to_string());
+            refresh_languages(browser.to_string());
+        }
+    }));
+
+    /* Keep the current selection when the new channel has it */
+    let saved_language = installed.map(|install| install.language.clone())
+        .filter(|language| !language.is_empty())
+        .or_else(|| config.language.clone());
+    rx.attach(None, clone!(@strong browser_combo, @strong language_combo, @strong ok_button => move |(browser, languages)| {
+        if browser_combo.active_id().as_deref() != Some(browser.as_str()) {
+            return Continue(true);
+        }
 
-    rx.attach(None, clone!(@strong language_combo, @strong ok_button => move |languages| {
+        let current_language = language_combo.active_id();
+        language_combo.remove_all();
         for lang in languages.iter() {
             language_combo.append(Some(&lang.0), &lang.1);
         }
 
-        let detected_language = detect_language(&languages);
+        let selected_language = current_language.as_deref()
+            .or(saved_language.as_deref())
+            .and_then(|lang| find_language(&languages, lang));
+        let detected_language = selected_language.or_else(|| detect_language(&languages));
         language_combo.set_active_id(Some(detected_language.unwrap_or("en-US")));
         ok_button.set_sensitive(true);
-        Continue(false)
+        Continue(true)
     }));
 
     window.show_all();
 }
 
+/*
+    Exit status of the command-line modes, so that scripts can tell what went wrong
+*/
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Config(_) => 2,
+        e if e.is_transient() => 3,
+        Error::Verification(_) => 4,
+        Error::Extraction(_) => 5,
+        Error::Io { .. } | Error::Cache(_) => 6,
+        Error::Tool { .. } => 7,
+        /* e.g. 404 for a version or language the server doesn't have */
+        Error::Http { .. } => 8,
+        Error::Cancelled => 130,
+        _ => 1,
+    }
+}
+
+/*
+    Headless install: same work as the dialog, but progress goes to the
+    terminal. Returns the process exit code.
+*/
+fn cli_install(datadir: &Path, options: &Options, system: &SystemConfig, source: &InstallSource) -> i32 {
+    let config = load_config(datadir).merged(system);
+    let channel = options.channel.as_deref()
+        .or_else(|| locked_channel(system).map(|channel| channel.0))
+        .unwrap_or("release");
+    let channel = match find_channel(channel) {
+        Some(channel) => channel,
+        None => {
+            eprintln!("Unknown channel: {}", channel);
+            return 2;
+        }
+    };
+
+    /* Reinstalling a channel reuses the settings it was installed with */
+    let installed = config.installs.get(channel.0);
+    let architecture = options.architecture.as_deref()
+        .or_else(|| installed.map(|install| install.architecture.as_str()))
+        .or(config.architecture.as_deref())
+        .or_else(|| architecture::detect());
+    /* Guessing would install a firefox this system can't run, e.g. on armhf or ppc64le */
+    let architecture = match architecture {
+        Some(architecture) => architecture,
+        None => {
+            eprintln!("Cannot determine the architecture of this system, choose one with --arch");
+            return 2;
+        }
+    };
+    if !ARCHITECTURES.iter().any(|elem| elem.0 == architecture) {
+        eprintln!("Unknown architecture: {}", architecture);
+        return 2;
+    }
+
+    let locks = system.check("channel", channel.0)
+        .and(system.check("architecture", architecture))
+        .and(options.lang.as_deref().map_or(Ok(()), |lang| system.check("language", lang)));
+    if let Err(e) = locks {
+        eprintln!("{}", e);
+        return 2;
+    }
+
+    let lang = options.lang.as_deref()
+        .or_else(|| installed.map(|install| install.language.as_str()).filter(|language| !language.is_empty()))
+        .or(config.language.as_deref())
+        .map(String::from);
+
+    /* A pin sticks until another version, or "latest", is asked for */
+    let source = &InstallSource {
+        version: options.version.as_deref()
+            .or_else(|| installed.and_then(|install| install.version.as_deref()))
+            .filter(|version| *version != LATEST_VERSION)
+            .map(String::from),
+        ..source.clone()
+    };
+    let mut downloader = match new_downloader(datadir, &source.network) {
+        Ok(downloader) => downloader,
+        Err(e) => {
+            eprintln!("{}", e);
+            return exit_code(&e);
+        }
+    };
+    let languages = channel_languages(&mut downloader, source, channel.0, architecture);
+    let lang = match lang {
+        Some(lang) => {
+            if find_language(&languages, &lang).is_none() {
+                eprintln!("Warning: {} does not seem to be available for {}", lang, channel.2);
+            }
+            lang
+        }
+        None => String::from(detect_language(&languages).unwrap_or("en-US")),
+    };
+
+    let appdir = get_appdir(datadir, channel.0);
+    let latest = match latest_version(&mut downloader, source, channel.0) {
+        Ok(latest) => Some(latest),
+        Err(e) => {
+            eprintln!("Cannot determine the latest version: {}", e);
+            None
+        }
+    };
+    report_warnings(&mut downloader);
+
+    /* Nightly keeps the same version number across builds, so it is always reinstalled */
+    if !options.force && channel.0 != "nightly" {
+        let up_to_date = match (&latest, installed_info(datadir, channel.0)) {
+            (Some(latest), Some(info)) => {
+                info.has_version(latest) && info.architecture == architecture && info.language == lang
+            }
+            _ => false
+        };
+        if up_to_date {
+            println!("{} {} is already installed, use --force to reinstall", channel.2, latest.unwrap_or_default());
+            return 0;
+        }
+    }
+
+    println!("Installing {} {}({}, {})",
+        channel.2,
+        latest.map(|latest| latest + " ").unwrap_or_default(),
+        architecture,
+        lang);
+    cli_run_install(datadir, &appdir, channel, architecture, &lang, source)
+}
+
+/*
+    Install an archive at hand, e.g. from a USB stick. Nothing is downloaded,
+    and the archive tells its architecture.
+*/
+fn cli_install_file(datadir: &Path, options: &Options, system: &SystemConfig, file: &Path) -> i32 {
+    let config = load_config(datadir).merged(system);
+    let channel = options.channel.as_deref()
+        .or_else(|| locked_channel(system).map(|channel| channel.0))
+        .unwrap_or("release");
+    let channel = match find_channel(channel) {
+        Some(channel) => channel,
+        None => {
+            eprintln!("Unknown channel: {}", channel);
+            return 2;
+        }
+    };
+    if let Err(e) = system.check("channel", channel.0) {
+        eprintln!("{}", e);
+        return 2;
+    }
+
+    /*
+        The archive does not tell its language: it is only recorded when
+        given, so that repairs download the same one. A pin is kept.
+    */
+    let lang = options.lang.clone().unwrap_or_default();
+    if let Err(e) = options.lang.as_deref().map_or(Ok(()), |lang| system.check("language", lang)) {
+        eprintln!("{}", e);
+        return 2;
+    }
+
+    let source = InstallSource {
+        file: Some(PathBuf::from(file)),
+        checksum: options.checksum.clone(),
+        version: config.installs.get(channel.0).and_then(|install| install.version.clone()),
+        ..InstallSource::default()
+    };
+    let appdir = get_appdir(datadir, channel.0);
+    println!("Installing {} from {}", channel.2, file.display());
+    cli_run_install(datadir, &appdir, channel, default_architecture(), &lang, &source)
+}
+
+/* Run an install, printing its progress, and return the exit code */
+fn cli_run_install(datadir: &Path, appdir: &Path, channel: &Channel, architecture: &str, lang: &str, source: &InstallSource) -> i32 {
+    let cancel = CancelToken::new();
+    let handler = ctrlc::set_handler(clone!(@strong cancel => move || {
+        cancel.cancel();
+    }));
+    if let Err(e) = handler {
+        eprintln!("Cannot handle Ctrl-C: {}", e);
+    }
+
+    let in_progress = std::cell::Cell::new(false);
+    let result = install(datadir, appdir, channel.1, architecture, lang, source, &cancel, |progress| {
+        match progress {
+            Progress::Status(text) => {
+                if in_progress.replace(false) {
+                    println!();
+                }
+                println!("{}", text);
+            }
+            Progress::Percent(current, total) => {
+                if total != -1 {
+                    print!("\r{:3}%", current * 100 / total.max(1));
+                } else {
+                    print!("\r{} bytes", current);
+                }
+                std::io::stdout().flush().ok();
+                in_progress.set(true);
+            }
+            Progress::Warning(text) => {
+                if in_progress.replace(false) {
+                    println!();
+                }
+                eprintln!("Warning: {}", text);
+            }
+            Progress::Transfer(transfer) => {
+                /* Padded to overwrite a longer previous line */
+                print!("\r{:<50}", transfer.text());
+                std::io::stdout().flush().ok();
+                in_progress.set(true);
+            }
+        }
+    });
+    if in_progress.get() {
+        println!();
+    }
+
+    match result {
+        Ok(_) => {
+            println!("Firefox installed into {}", appdir.display());
+            0
+        }
+        Err(Error::Cancelled) => {
+            eprintln!("Installation cancelled");
+            exit_code(&Error::Cancelled)
+        }
+        Err(e) => {
+            eprintln!("Installation failed: {}", e);
+            exit_code(&e)
+        }
+    }
+}
+
+/*
+    Report what is installed, for humans or (with --json) for inventory scripts
+*/
+fn cli_status(datadir: &Path, options: &Options, config: &Config, system: &SystemConfig) -> i32 {
+    let launched = launch_channel(datadir, system).0;
+    let channels: Vec<&Channel> = match &options.channel {
+        Some(channel) => match find_channel(channel) {
+            Some(channel) => vec![channel],
+            None => {
+                eprintln!("Unknown channel: {}", channel);
+                return 2;
+            }
+        },
+        None => CHANNELS.iter().collect(),
+    };
+
+    let mut report = Vec::new();
+    for channel in channels {
+        let appdir = get_appdir(datadir, channel.0);
+        if !is_installed(datadir, channel.0) {
+            report.push(serde_json::json!({
+                "channel": channel.0,
+                "installed": false,
+            }));
+            continue;
+        }
+
+        /* Installs made by older versions have no install.json */
+        let mut info = InstallInfo::load(&get_install_info_file(&appdir)).unwrap_or_else(|_| {
+            let mut info = InstallInfo::default();
+            if let Some(install) = config.installs.get(channel.0) {
+                info.architecture = install.architecture.clone();
+                info.language = install.language.clone();
+            }
+            info
+        });
+        if let Err(e) = info.refresh(&appdir.join("firefox")) {
+            eprintln!("{}", e);
+        }
+
+        report.push(serde_json::json!({
+            "channel": channel.0,
+            "installed": true,
+            "path": appdir.join("firefox"),
+            "name": info.name,
+            "version": info.full_version(),
+            "build_id": info.build_id,
+            "update_channel": info.update_channel,
+            "architecture": info.architecture,
+            "language": Some(&info.language).filter(|language| !language.is_empty()),
+            "installed_at": if info.installed != 0 { serde_json::json!(info.installed) } else { serde_json::Value::Null },
+            "default": channel.0 == launched,
+            "pinned": config.installs.get(channel.0).is_some_and(|install| install.version.is_some()),
+        }));
+    }
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+        return 0;
+    }
+
+    for entry in report.iter() {
+        let channel = entry["channel"].as_str().unwrap_or_default();
+        if entry["installed"] != true {
+            println!("{}: not installed", channel);
+            continue;
+        }
+
+        println!("{}: {} {} (build {}, update channel {}){}{}",
+            channel,
+            entry["name"].as_str().unwrap_or_default(),
+            entry["version"].as_str().unwrap_or_default(),
+            entry["build_id"].as_str().unwrap_or_default(),
+            entry["update_channel"].as_str().unwrap_or_default(),
+            if entry["default"] == true { " [default]" } else { "" },
+            if entry["pinned"] == true { " [pinned]" } else { "" });
+        println!("    architecture: {}, language: {}",
+            entry["architecture"].as_str().unwrap_or("unknown"),
+            entry["language"].as_str().unwrap_or("unknown"));
+        if let Some(installed) = entry["installed_at"].as_i64() {
+            let date = Utc.timestamp_opt(installed, 0).single();
+            if let Some(date) = date {
+                println!("    installed: {}", date.with_timezone(&Local).format("%Y-%m-%d %H:%M"));
+            }
+        }
+        println!("    path: {}", entry["path"].as_str().unwrap_or_default());
+    }
+    0
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn cli_cache(cachedir: &Path, options: &Options) -> i32 {
+    let mut downloader = match Downloader::new(cachedir) {
+        Ok(downloader) => downloader,
+        Err(e) => {
+            eprintln!("{}", e);
+            return exit_code(&e);
+        }
+    };
+
+    report_warnings(&mut downloader);
+
+    if options.cache_clear || options.cache_prune {
+        let freed = if options.cache_clear { downloader.clear() } else { downloader.prune() };
+        println!("Freed {}", format_size(freed));
+
+        /* e.g. a read-only cache directory: the same status as a cache error */
+        let warnings = downloader.take_warnings();
+        for warning in warnings.iter() {
+            eprintln!("Warning: {}", warning);
+        }
+        return if warnings.is_empty() { 0 } else { 6 };
+    }
+
+    /* Entries whose file is gone are not worth listing */
+    downloader.cleanup();
+    report_warnings(&mut downloader);
+    let entries = downloader.entries();
+    for entry in entries.iter() {
+        let last_used = Utc.timestamp_opt(entry.last_used, 0).single()
+            .map(|date| date.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("{:>10}  {}  {}{}{}",
+            format_size(entry.size),
+            last_used,
+            entry.filename,
+            if entry.complete { "" } else { " [partial]" },
+            if entry.fresh { "" } else { " [stale]" });
+        println!("    {}", entry.url);
+    }
+    println!("{} in {} files", format_size(entries.iter().map(|entry| entry.size).sum()), entries.len());
+    0
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(args.clone()) {
+        Ok(options) => options,
+        Err(e) if has_installer_mode(&args) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        /* e.g. a trailing --channel: let firefox make sense of it */
+        Err(_) => Options { firefox_args: args, ..Options::default() },
+    };
 
     let datadir = get_datadir();
     let cachedir = get_cachedir(&datadir);
-    let appdir = Path::new(&datadir).join("app");
+    migrate_legacy_install(&datadir);
+    let system = load_system_config();
+    let config = load_config(&datadir).merged(&system);
+
+    let channel = match &options.channel {
+        Some(channel) => match find_channel(channel) {
+            Some(channel) => channel,
+            None => {
+                eprintln!("Unknown channel: {}", channel);
+                process::exit(2);
+            }
+        },
+        None => launch_channel(&datadir, &system),
+    };
+    /* The dialog offers the last channel chosen, unless told otherwise */
+    let dialog_channel = match &options.channel {
+        Some(_) => channel,
+        None => config.channel.as_deref().and_then(find_channel).unwrap_or(channel),
+    };
+    let appdir = get_appdir(&datadir, channel.0);
     let exe = Path::new(&appdir).join("firefox/firefox");
-    let reset = env::args().skip(1).find(|s| s == "--reset").is_some();
+    let reset = options.reset;
+    let source = InstallSource {
+        mirror: options.mirror.clone().or_else(|| system.mirror.clone()),
+        network: config.network.clone(),
+        ..InstallSource::default()
+    };
+    recover_install(&appdir);
+
+    if let Some(file) = &options.from_file {
+        process::exit(cli_install_file(&datadir, &options, &system, file));
+    }
+    if options.checksum.is_some() {
+        eprintln!("--checksum is only used with --from-file");
+        process::exit(2);
+    }
+
+    if options.install {
+        process::exit(cli_install(&datadir, &options, &system, &source));
+    }
+
+    if options.status {
+        process::exit(cli_status(&datadir, &options, &config, &system));
+    }
+
+    if options.cache_list || options.cache_prune || options.cache_clear {
+        process::exit(cli_cache(&cachedir, &options));
+    }
+
+    if options.rollback {
+        match rollback(&appdir) {
+            Ok(_) => {
+                println!("Restored the previous {} installation", channel.2);
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Rollback failed: {}", e);
+                process::exit(exit_code(&e));
+            }
+        }
+    }
+
+    /* Whatever is installed can be launched, locks only apply to installing */
+    if reset || !exe.exists() {
+        if let Err(e) = system.check("channel", channel.0) {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
 
     let application = Rc::new(Application::builder()
         .application_id("io.degaart.firefox-user-installer")
         .build());
 
     application.connect_activate(clone!(@strong application => move |_| {
-        if reset || !exe.exists() {
-            build_ui(Rc::clone(&application));
+        if !reset && exe.exists() {
+            run_app(channel.0);
+        } else if system.all_locked() {
+            /* Nothing to choose, install straight away */
+            on_ok(Rc::clone(&application), channel.1,
+                system.architecture.as_deref().unwrap_or_default(),
+                system.language.as_deref().unwrap_or_default(),
+                &source);
+        } else if let Some(install) = config.installs.get(channel.0).filter(|_| !reset) {
+            /* Installed before but broken or removed since: repair it with the same settings */
+            let source = InstallSource { version: install.version.clone(), ..source.clone() };
+            /* Installed from an archive in an unknown language */
+            let language = match install.language.as_str() {
+                "" => config.language.clone()
+                    .unwrap_or_else(|| String::from(detect_language(&fallback_languages()).unwrap_or("en-US"))),
+                language => String::from(language),
+            };
+            on_ok(Rc::clone(&application), channel.1, &install.architecture, &language, &source);
         } else {
-            run_app();
+            build_ui(Rc::clone(&application), dialog_channel, &config, &system, &source);
         }
     }));
 
@@ -0,0 +1,184 @@
+use crate::{Downloader, Error};
+use crate::languages::FALLBACK_LANGUAGES;
+
+/*
+    A mirror is laid out like https://archive.mozilla.org/pub/firefox, e.g.
+    <base>/releases/121.0/linux-x86_64/de/firefox-121.0.tar.bz2, with
+    SHA512SUMS, SHA512SUMS.asc and KEY in <base>/releases/121.0. The base can
+    be an http(s) url or a file:// directory.
+*/
+
+/* Mozilla's own archive, which has every release ever made */
+pub const ARCHIVE_URL: &str = "https://archive.mozilla.org/pub/firefox";
+
+/* Directory name of a mozilla os id ("linux64") on the mirror */
+pub fn platform(os: &str) -> Option<&'static str> {
+    match os {
+        "linux64" => Some("linux-x86_64"),
+        "linux" => Some("linux-i686"),
+        "linux64-aarch64" => Some("linux-aarch64"),
+        _ => None,
+    }
+}
+
+/* Whether a version directory (e.g. "121.0", "122.0b3", "115.6.0esr") belongs to `channel` */
+fn is_channel_version(channel: &str, version: &str) -> bool {
+    if !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return false;
+    }
+    let is_esr = version.ends_with("esr");
+    let is_beta = version.contains('b');
+    match channel {
+        "release" => !is_esr && !is_beta && version.chars().all(|c| c.is_ascii_digit() || c == '.'),
+        "esr" => is_esr,
+        "beta" => is_beta && !is_esr,
+        _ => false,
+    }
+}
+
+/* Numeric parts of a version, for ordering: "122.0b3" is [122, 0, 3] */
+pub fn version_key(version: &str) -> Vec<u64> {
+    version.split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn releases_url(base_url: &str) -> String {
+    format!("{}/releases", base_url.trim_end_matches('/'))
+}
+
+/* Versions of `channel` available on the mirror, oldest first */
+pub fn versions(downloader: &mut Downloader, base_url: &str, channel: &str) -> Result<Vec<String>,Error> {
+    if !["release", "beta", "esr"].contains(&channel) {
+        return Err(Error::Config(format!("The {} channel is not available from a mirror", channel)));
+    }
+
+    let mut result: Vec<String> = downloader.list_directory(&releases_url(base_url))?
+        .into_iter()
+        .filter(|version| is_channel_version(channel, version))
+        .collect();
+    result.sort_by_key(|version| version_key(version));
+    Ok(result)
+}
+
+pub fn latest_version(downloader: &mut Downloader, base_url: &str, channel: &str) -> Result<String,Error> {
+    versions(downloader, base_url, channel)?
+        .pop()
+        .ok_or_else(|| Error::new(&format!("No {} version on {}", channel, base_url)))
+}
+
+/* (code, native name) of the locales the mirror has for a version */
+pub fn languages(downloader: &mut Downloader, base_url: &str, version: &str, os: &str) -> Result<Vec<(String,String)>,Error> {
+    let platform = platform(os)
+        .ok_or_else(|| Error::Config(format!("Unknown architecture: {}", os)))?;
+    let url = format!("{}/{}/{}", releases_url(base_url), version, platform);
+    let mut result: Vec<(String,String)> = downloader.list_directory(&url)?
+        .into_iter()
+        .filter(|code| code != "xpi")
+        .map(|code| {
+            let name = FALLBACK_LANGUAGES.iter()
+                .find(|elem| elem.0 == code)
+                .map(|elem| String::from(elem.1))
+                .unwrap_or_else(|| code.clone());
+            (code, name)
+        })
+        .collect();
+    result.sort_by_key(|(code, name)| (code != "en-US", name.to_lowercase()));
+    Ok(result)
+}
+
+/* Url of the archive of a version, whatever its compression */
+pub fn archive_url(downloader: &mut Downloader, base_url: &str, version: &str, os: &str, lang: &str) -> Result<String,Error> {
+    let platform = platform(os)
+        .ok_or_else(|| Error::Config(format!("Unknown architecture: {}", os)))?;
+    let dir = format!("{}/{}/{}/{}", releases_url(base_url), version, platform, lang);
+    let files = downloader.list_directory(&dir)?;
+    ["tar.xz", "tar.bz2"].iter()
+        .map(|extension| format!("firefox-{}.{}", version, extension))
+        .find(|name| files.contains(name))
+        .map(|name| format!("{}/{}", dir, name))
+        .ok_or_else(|| Error::new(&format!("No firefox {} archive in {}", version, dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /* A file:// mirror with empty archives */
+    fn mirror(files: &[&str]) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, b"").unwrap();
+        }
+        let url = reqwest::Url::from_directory_path(dir.path()).unwrap().to_string();
+        (dir, url)
+    }
+
+    fn downloader(dir: &Path) -> Downloader {
+        Downloader::new(&dir.join("cache")).unwrap()
+    }
+
+    #[test]
+    fn channel_versions() {
+        let (dir, url) = mirror(&[
+            "releases/120.0/SHA512SUMS",
+            "releases/120.0.1/SHA512SUMS",
+            "releases/121.0/SHA512SUMS",
+            "releases/122.0b3/SHA512SUMS",
+            "releases/122.0b10/SHA512SUMS",
+            "releases/115.6.0esr/SHA512SUMS",
+            "releases/115.10.0esr/SHA512SUMS",
+            "releases/latest/README.txt",
+            "releases/121.0-funnelcake/SHA512SUMS",
+        ]);
+        let mut downloader = downloader(dir.path());
+
+        assert_eq!(versions(&mut downloader, &url, "release").unwrap(), vec![ "120.0", "120.0.1", "121.0" ]);
+        assert_eq!(versions(&mut downloader, &url, "beta").unwrap(), vec![ "122.0b3", "122.0b10" ]);
+        assert_eq!(versions(&mut downloader, &url, "esr").unwrap(), vec![ "115.6.0esr", "115.10.0esr" ]);
+        assert!(matches!(versions(&mut downloader, &url, "nightly"), Err(Error::Config(_))));
+
+        assert_eq!(latest_version(&mut downloader, &url, "release").unwrap(), "121.0");
+        assert_eq!(latest_version(&mut downloader, &url, "beta").unwrap(), "122.0b10");
+        assert_eq!(latest_version(&mut downloader, &url, "esr").unwrap(), "115.10.0esr");
+    }
+
+    #[test]
+    fn no_version() {
+        let (dir, url) = mirror(&[ "releases/121.0/SHA512SUMS" ]);
+        let mut esr = downloader(dir.path());
+        assert!(versions(&mut esr, &url, "esr").unwrap().is_empty());
+        assert!(latest_version(&mut esr, &url, "esr").is_err());
+
+        /* Not a mirror at all */
+        let (dir, url) = mirror(&[]);
+        let mut empty = downloader(dir.path());
+        assert!(matches!(versions(&mut empty, &url, "release"), Err(Error::Io { .. })));
+    }
+
+    #[test]
+    fn archive_urls() {
+        let (dir, url) = mirror(&[
+            "releases/121.0/linux-x86_64/de/firefox-121.0.tar.bz2",
+            "releases/121.0/linux-x86_64/de/firefox-121.0.tar.xz",
+            "releases/121.0/linux-x86_64/fr/firefox-121.0.tar.bz2",
+            "releases/121.0/linux-i686/fr/firefox-121.0.tar.bz2",
+        ]);
+        let base = url.trim_end_matches('/');
+        let mut downloader = downloader(dir.path());
+
+        assert_eq!(archive_url(&mut downloader, &url, "121.0", "linux64", "de").unwrap(),
+            format!("{}/releases/121.0/linux-x86_64/de/firefox-121.0.tar.xz", base));
+        assert_eq!(archive_url(&mut downloader, &url, "121.0", "linux64", "fr").unwrap(),
+            format!("{}/releases/121.0/linux-x86_64/fr/firefox-121.0.tar.bz2", base));
+        assert_eq!(archive_url(&mut downloader, &url, "121.0", "linux", "fr").unwrap(),
+            format!("{}/releases/121.0/linux-i686/fr/firefox-121.0.tar.bz2", base));
+        assert!(archive_url(&mut downloader, &url, "121.0", "linux", "de").is_err());
+        assert!(archive_url(&mut downloader, &url, "122.0", "linux64", "de").is_err());
+        assert!(matches!(archive_url(&mut downloader, &url, "121.0", "ppc64le", "de"), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Downloader, Error};
+
+pub const PRODUCT_DETAILS_URL: &str = "https://product-details.mozilla.org/1.0";
+
+/* Key of each channel in firefox_versions.json */
+fn version_key(channel: &str) -> Option<&'static str> {
+    match channel {
+        "release" => Some("LATEST_FIREFOX_VERSION"),
+        "beta" => Some("LATEST_FIREFOX_DEVEL_VERSION"),
+        "devedition" => Some("FIREFOX_DEVEDITION"),
+        "nightly" => Some("FIREFOX_NIGHTLY"),
+        "esr" => Some("FIREFOX_ESR"),
+        _ => None,
+    }
+}
+
+/*
+    Current version of a channel ("release", "beta", "devedition", "nightly"
+    or "esr"), according to product-details at `base_url`
+*/
+pub fn latest_version(downloader: &mut Downloader, base_url: &str, channel: &str) -> Result<String,Error> {
+    let key = version_key(channel)
+        .ok_or_else(|| Error::Config(format!("Unknown channel: {}", channel)))?;
+    let url = format!("{}/firefox_versions.json", base_url.trim_end_matches('/'));
+    let contents = downloader.download_to_string(&url)?;
+    let versions: serde_json::Value = serde_json::from_str(&contents)?;
+    versions[key].as_str()
+        .filter(|version| !version.is_empty())
+        .map(String::from)
+        .ok_or_else(|| Error::new(&format!("No {} version in {}", channel, url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, TestServer};
+
+    const FIREFOX_VERSIONS: &str = r#"{
+        "FIREFOX_DEVEDITION": "122.0b3",
+        "FIREFOX_ESR": "115.8.0esr",
+        "FIREFOX_NIGHTLY": "124.0a1",
+        "LATEST_FIREFOX_DEVEL_VERSION": "122.0b3",
+        "LATEST_FIREFOX_VERSION": "121.0.1"
+    }"#;
+
+    fn fixture_server() -> TestServer {
+        TestServer::start(|path, _| match path {
+            "/1.0/firefox_versions.json" => response("200 OK", &[ "Content-Type: application/json" ], FIREFOX_VERSIONS.as_bytes()),
+            _ => response("404 Not Found", &[], b""),
+        })
+    }
+
+    #[test]
+    fn latest_versions() {
+        let server = fixture_server();
+        let cachedir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let base_url = format!("{}/1.0", server.url);
+        for (channel, expected) in [
+            ("release", "121.0.1"),
+            ("beta", "122.0b3"),
+            ("devedition", "122.0b3"),
+            ("nightly", "124.0a1"),
+            ("esr", "115.8.0esr"),
+        ] {
+            assert_eq!(latest_version(&mut downloader, &base_url, channel).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn unknown_channel() {
+        let server = fixture_server();
+        let cachedir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let base_url = format!("{}/1.0", server.url);
+        assert!(matches!(latest_version(&mut downloader, &base_url, "aurora"), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn missing_fixture() {
+        let server = fixture_server();
+        let cachedir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+        let base_url = format!("{}/2.0", server.url);
+        assert!(matches!(latest_version(&mut downloader, &base_url, "release"), Err(Error::Http { status: 404, .. })));
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/* Helpers shared by the unit tests */
+
+/*
+    Minimal HTTP server for tests. Each connection gets one request, answered
+    with whatever `respond` returns for its path and raw headers, then closed.
+*/
+pub struct TestServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+    pub fn start<F>(respond: F) -> TestServer
+    where
+        F: Fn(&str, &str) -> Vec<u8> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buf = [ 0u8; 4096 ];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(len) => request.extend_from_slice(&buf[0..len]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                log.lock().unwrap().push(request.clone());
+                stream.write_all(&respond(&path, &request)).ok();
+            }
+        });
+        TestServer { url, requests }
+    }
+
+    /* Raw requests received so far */
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/* A complete response; `headers` are "Name: value" lines */
+pub fn response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut result = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n", status, body.len()).into_bytes();
+    for header in headers {
+        result.extend_from_slice(header.as_bytes());
+        result.extend_from_slice(b"\r\n");
+    }
+    result.extend_from_slice(b"\r\n");
+    result.extend_from_slice(body);
+    result
+}
+
+/* A response announcing all of `body` but cut after `sent` bytes */
+pub fn truncated_response(status: &str, headers: &[&str], body: &[u8], sent: usize) -> Vec<u8> {
+    let mut result = response(status, headers, body);
+    result.truncate(result.len() - body.len() + sent);
+    result
+}
+
+/* A .tar.bz2 made of (path, contents) files */
+pub fn tar_bz2(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *contents).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/* Start of a little-endian ELF executable of the given class and e_machine */
+pub fn elf_header(class: u8, machine: u16) -> Vec<u8> {
+    let mut result = vec![ 0x7f, b'E', b'L', b'F', class, 1, 1, 0 ];
+    result.resize(16, 0);
+    result.extend_from_slice(&2u16.to_le_bytes());
+    result.extend_from_slice(&machine.to_le_bytes());
+    result.resize(64, 0);
+    result
+}
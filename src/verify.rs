@@ -0,0 +1,333 @@
+use crate::{Downloader, Error};
+use sha2::{Digest, Sha256, Sha512};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process;
+
+/*
+    Fingerprint of the "Mozilla Software Releases <release@mozilla.com>"
+    primary key. The key itself is not bundled: it is fetched from the KEY
+    file next to SHA512SUMS, which mirrors must therefore carry, and only
+    signatures made by this key (or one of its subkeys) are accepted.
+*/
+pub const MOZILLA_KEY_FINGERPRINT: &str = "14F26682D0916CDD81E37B6D61B7B526D98F0353";
+
+pub fn sha512_file(path: &Path) -> Result<String,Error> {
+    hash_file::<Sha512>(path)
+}
+
+fn hash_file<D: Digest>(path: &Path) -> Result<String,Error> {
+    let mut f = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut hasher = D::new();
+    let mut buf = [ 0u8; 65536 ];
+    loop {
+        let len = f.read(&mut buf).map_err(|e| Error::io(path, e))?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[0..len]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/*
+    Find the hash of `name` in a SHA512SUMS file ("<hash>  <name>" lines)
+*/
+fn find_checksum(sums: &str, name: &str) -> Option<String> {
+    sums.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()?;
+        if fields.next()? == name {
+            Some(hash.to_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+/*
+    Find the sha512 hash of `name` in a nightly .checksums file
+    ("<hash> <algorithm> <size> <name>" lines)
+*/
+fn find_nightly_checksum(checksums: &str, name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 4 && fields[1] == "sha512" && fields[3] == name {
+            Some(fields[0].to_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+/*
+    Check the detached signature `signature` of `data` with gpg, using `key`
+    as the only known key
+*/
+pub fn verify_signature(key: &str, data: &str, signature: &str) -> Result<(),Error> {
+    verify_signature_by(key, data, signature, MOZILLA_KEY_FINGERPRINT)
+}
+
+/* Same, with the fingerprint of the primary key that must have signed */
+fn verify_signature_by(key: &str, data: &str, signature: &str, fingerprint: &str) -> Result<(),Error> {
+    let workdir = tempfile::Builder::new()
+        .prefix("ffui")
+        .tempdir()?;
+    let homedir = workdir.path().join("gnupg");
+    std::fs::create_dir(&homedir)?;
+    let key_file = workdir.path().join("KEY");
+    let data_file = workdir.path().join("SHA512SUMS");
+    let signature_file = workdir.path().join("SHA512SUMS.asc");
+    std::fs::write(&key_file, key)?;
+    std::fs::write(&data_file, data)?;
+    std::fs::write(&signature_file, signature)?;
+
+    let status = process::Command::new("gpg")
+        .arg("--homedir").arg(&homedir)
+        .arg("--batch")
+        .arg("--quiet")
+        .arg("--import")
+        .arg(&key_file)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()
+        .map_err(|e| Error::Tool { program: String::from("gpg"), source: e })?;
+    if !status.success() {
+        return Err(Error::Verification(String::from("Cannot import Mozilla release key")));
+    }
+
+    let output = process::Command::new("gpg")
+        .arg("--homedir").arg(&homedir)
+        .arg("--batch")
+        .arg("--status-fd").arg("1")
+        .arg("--verify")
+        .arg(&signature_file)
+        .arg(&data_file)
+        .stderr(process::Stdio::null())
+        .output()
+        .map_err(|e| Error::Tool { program: String::from("gpg"), source: e })?;
+
+    /* [GNUPG:] VALIDSIG <fpr> <date> <timestamp> <expire> <ver> <reserved> <algo> <hash> <class> <primary-fpr> */
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let valid = stdout.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 3
+            && fields[0] == "[GNUPG:]"
+            && fields[1] == "VALIDSIG"
+            && fields.last().unwrap().eq_ignore_ascii_case(fingerprint)
+    });
+    if !valid {
+        return Err(Error::Verification(String::from("Bad signature on SHA512SUMS")));
+    }
+    Ok(())
+}
+
+/*
+    Verify an archive downloaded from `location`, the url it actually came
+    from after redirects (see Downloader::location).
+
+    Releases are laid out as .../releases/<version>/<platform>/<lang>/<file>,
+    with SHA512SUMS, SHA512SUMS.asc and KEY in the <version> directory.
+    Nightlies only ship an unsigned <name>.checksums file next to the archive,
+    so for them only the hash is checked. A mismatching archive is evicted
+    from the cache.
+*/
+pub fn verify_download(downloader: &mut Downloader, location: &str, archive: &Path) -> Result<(),Error> {
+    verify_download_signed_by(downloader, location, archive, MOZILLA_KEY_FINGERPRINT)
+}
+
+fn verify_download_signed_by(downloader: &mut Downloader, location: &str, archive: &Path, fingerprint: &str) -> Result<(),Error> {
+    let location = reqwest::Url::parse(location)
+        .map_err(|e| Error::Verification(format!("Invalid download location {}: {}", location, e)))?;
+    let segments: Vec<&str> = location.path_segments()
+        .map(|segments| segments.collect())
+        .unwrap_or_default();
+    let filename = *segments.last()
+        .ok_or_else(|| Error::Verification(String::from("Invalid download location")))?;
+
+    let expected = if let Some(index) = segments.iter().position(|s| *s == "releases") {
+        if segments.len() < index + 5 {
+            return Err(Error::Verification(format!("Unexpected download location: {}", location)));
+        }
+
+        let mut base = location.clone();
+        base.set_query(None);
+        base.set_path(&segments[..index + 2].join("/"));
+        let base = base.as_str().trim_end_matches('/');
+        let sums = downloader.download_to_string(&format!("{}/SHA512SUMS", base))?;
+        let signature = downloader.download_to_string(&format!("{}/SHA512SUMS.asc", base))?;
+        let key = downloader.download_to_string(&format!("{}/KEY", base))?;
+        verify_signature_by(&key, &sums, &signature, fingerprint)?;
+
+        let name = segments[index + 2..].join("/");
+        find_checksum(&sums, &name)
+            .ok_or_else(|| Error::Verification(format!("{} is not listed in SHA512SUMS", name)))?
+    } else {
+        let stem = filename
+            .trim_end_matches(".tar.bz2")
+            .trim_end_matches(".tar.xz");
+        let mut checksums_url = location.clone();
+        checksums_url.set_query(None);
+        checksums_url.path_segments_mut()
+            .map_err(|_| Error::Verification(String::from("Invalid download location")))?
+            .pop()
+            .push(&format!("{}.checksums", stem));
+        let checksums = downloader.download_to_string(checksums_url.as_str())?;
+        find_nightly_checksum(&checksums, filename)
+            .ok_or_else(|| Error::Verification(format!("{} is not listed in checksums", filename)))?
+    };
+
+    let actual = sha512_file(archive)?;
+    if actual != expected {
+        downloader.evict(location.as_str());
+        return Err(Error::Verification(format!("Checksum mismatch for {}", filename)));
+    }
+    Ok(())
+}
+
+/*
+    Check a local archive against a checksum given by the user: a sha512 or
+    sha256 hash in hex, as printed by sha512sum or sha256sum
+*/
+pub fn verify_checksum(archive: &Path, checksum: &str) -> Result<(),Error> {
+    let expected = checksum.trim().to_lowercase();
+    let actual = match expected.len() {
+        128 => sha512_file(archive)?,
+        64 => hash_file::<Sha256>(archive)?,
+        _ => return Err(Error::Config(format!("Not a sha512 or sha256 checksum: {}", checksum))),
+    };
+    if actual != expected {
+        return Err(Error::Verification(format!("Checksum mismatch for {}", archive.display())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, TestServer};
+    use std::collections::HashMap;
+
+    const ARCHIVE: &str = "linux-x86_64/de/firefox-121.0.tar.bz2";
+
+    fn gpg(homedir: &Path, args: &[&str]) -> String {
+        let output = process::Command::new("gpg")
+            .arg("--homedir").arg(homedir)
+            .args([ "--batch", "--quiet", "--pinentry-mode", "loopback", "--passphrase", "" ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "gpg {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /* A signing key standing in for Mozilla's: (gnupg home, armored key, fingerprint) */
+    fn signing_key() -> (tempfile::TempDir, String, String) {
+        let homedir = tempfile::Builder::new().prefix("gpg").tempdir().unwrap();
+        gpg(homedir.path(), &[ "--quick-gen-key", "Test Releases <release@example.com>", "ed25519", "sign", "never" ]);
+        let key = gpg(homedir.path(), &[ "--armor", "--export" ]);
+        let fingerprint = gpg(homedir.path(), &[ "--with-colons", "--fingerprint" ]).lines()
+            .find_map(|line| line.strip_prefix("fpr:"))
+            .and_then(|line| line.split(':').nth(8))
+            .map(String::from)
+            .unwrap();
+        (homedir, key, fingerprint)
+    }
+
+    /* Files of releases/121.0, SHA512SUMS listing `sums` and signed with the key in `homedir` */
+    fn release(homedir: &Path, key: &str, archive: &[u8], sums: &str) -> HashMap<String,Vec<u8>> {
+        let sums_file = homedir.join("SHA512SUMS");
+        std::fs::write(&sums_file, sums).unwrap();
+        let signature = gpg(homedir, &[ "--armor", "--detach-sign", "--output", "-", sums_file.to_str().unwrap() ]);
+
+        let mut result = HashMap::new();
+        result.insert(String::from("SHA512SUMS"), sums.as_bytes().to_vec());
+        result.insert(String::from("SHA512SUMS.asc"), signature.into_bytes());
+        result.insert(String::from("KEY"), key.as_bytes().to_vec());
+        result.insert(String::from(ARCHIVE), archive.to_vec());
+        result
+    }
+
+    /* The release as a file:// mirror, returns the url of the archive */
+    fn file_mirror(dir: &Path, files: &HashMap<String,Vec<u8>>) -> String {
+        let base = dir.join("releases/121.0");
+        for (name, contents) in files {
+            let path = base.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        reqwest::Url::from_file_path(base.join(ARCHIVE)).unwrap().to_string()
+    }
+
+    fn sums(archive: &[u8], name: &str) -> String {
+        format!("{:x}  {}\n{:x}  linux-i686/de/firefox-121.0.tar.bz2\n", Sha512::digest(archive), name, Sha512::digest(b"other"))
+    }
+
+    fn verify(files: &HashMap<String,Vec<u8>>, fingerprint: &str) -> Result<(),Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let location = file_mirror(dir.path(), files);
+        let mut downloader = Downloader::new(&dir.path().join("cache")).unwrap();
+        let archive = dir.path().join("releases/121.0").join(ARCHIVE);
+        verify_download_signed_by(&mut downloader, &location, &archive, fingerprint)
+    }
+
+    #[test]
+    fn signed_release() {
+        let (homedir, key, fingerprint) = signing_key();
+        let archive = b"firefox 121.0";
+        let files = release(homedir.path(), &key, archive, &sums(archive, ARCHIVE));
+        verify(&files, &fingerprint).unwrap();
+
+        /* Signed, but not by the key we trust */
+        assert!(matches!(verify(&files, MOZILLA_KEY_FINGERPRINT), Err(Error::Verification(_))));
+
+        let mut tampered = files.clone();
+        tampered.insert(String::from("SHA512SUMS"), sums(b"something else", ARCHIVE).into_bytes());
+        assert!(matches!(verify(&tampered, &fingerprint), Err(Error::Verification(_))));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let (homedir, key, fingerprint) = signing_key();
+        let files = release(homedir.path(), &key, b"firefox 121.0", &sums(b"something else", ARCHIVE));
+        match verify(&files, &fingerprint) {
+            Err(Error::Verification(message)) => assert!(message.contains("mismatch"), "{}", message),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_listed() {
+        let (homedir, key, fingerprint) = signing_key();
+        let archive = b"firefox 121.0";
+        let files = release(homedir.path(), &key, archive, &sums(archive, "linux-x86_64/fr/firefox-121.0.tar.bz2"));
+        match verify(&files, &fingerprint) {
+            Err(Error::Verification(message)) => assert!(message.contains("not listed"), "{}", message),
+            other => panic!("expected an unlisted archive, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatch_evicts_download() {
+        let (homedir, key, fingerprint) = signing_key();
+        let files = release(homedir.path(), &key, b"firefox 121.0", &sums(b"something else", ARCHIVE));
+        let server = TestServer::start(move |path, _| {
+            match path.strip_prefix("/releases/121.0/").and_then(|name| files.get(name)) {
+                Some(contents) => response("200 OK", &[ "Cache-Control: max-age=3600" ], contents),
+                None => response("404 Not Found", &[], b""),
+            }
+        });
+        let cachedir = tempfile::tempdir().unwrap();
+        let destdir = tempfile::tempdir().unwrap();
+        let mut downloader = Downloader::new(cachedir.path()).unwrap();
+
+        let url = format!("{}/releases/121.0/{}", server.url, ARCHIVE);
+        let archive = downloader.download(&url, destdir.path()).unwrap();
+        assert!(downloader.entries().iter().any(|entry| entry.url == url));
+
+        let location = downloader.location(&url).unwrap();
+        assert!(matches!(verify_download_signed_by(&mut downloader, &location, &archive, &fingerprint), Err(Error::Verification(_))));
+        assert!(!downloader.entries().iter().any(|entry| entry.url == url));
+    }
+}